@@ -0,0 +1,143 @@
+//! Compares the size and cost of broadcasting typical chat traffic with and
+//! without permessage-deflate (see `src/compression.rs`).
+//!
+//! Run with `cargo bench --bench compression`. Settings can be tried out the
+//! same way as on the server, with `CHAT_WS_COMPRESSION_THRESHOLD` and
+//! `CHAT_WS_COMPRESSION_LEVEL`.
+
+#![allow(dead_code)]
+
+#[path = "../src/compression.rs"]
+mod compression;
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use compression::PerMessageDeflate;
+use protocol::{Encoding, Outbound, ServerFrame};
+use soketto::base::{Header, OpCode};
+use soketto::extension::Extension;
+use soketto::Storage;
+
+const ITERATIONS: u32 = 2_000;
+/// Clients in the room. Every connection has its own DEFLATE window, so a
+/// broadcast is compressed once per recipient.
+const RECIPIENTS: usize = 50;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn workload() -> Vec<(&'static str, ServerFrame)> {
+    let message = |content: String| ServerFrame::Message {
+        id: Some(42),
+        sender: "alice".into(),
+        content,
+        sent_at: 1_700_000_000,
+    };
+    vec![
+        ("short message", message("see you in five".into())),
+        ("system notice", ServerFrame::system("bob has joined the chat.")),
+        (
+            "paragraph",
+            message("The deploy went out at noon and the error rate is back to normal. ".repeat(8)),
+        ),
+        (
+            "pasted log",
+            message(
+                (0..60)
+                    .map(|i| format!("2024-05-01T12:00:{:02}Z INFO request handled path=/api/v1/rooms status=200\n", i))
+                    .collect(),
+            ),
+        ),
+        (
+            "file announcement",
+            ServerFrame::File {
+                id: Some(43),
+                sender: "carol".into(),
+                file_id: "6f1c2d7e-9a43-4c1b-8d4e-1f2a3b4c5d6e".into(),
+                filename: "quarterly-report-final-v2.pdf".into(),
+                sent_at: 1_700_000_000,
+                image: None,
+            },
+        ),
+    ]
+}
+
+/// The payload as it goes on the wire through `extension`.
+fn send(extension: &mut PerMessageDeflate, outbound: &Outbound) -> Vec<u8> {
+    let (opcode, data) = match outbound {
+        Outbound::Text(text) => (OpCode::Text, text.as_bytes()),
+        Outbound::Binary(data) => (OpCode::Binary, &data[..]),
+    };
+    let mut header = Header::new(opcode);
+    let mut storage = Storage::Shared(data);
+    extension.encode(&mut header, &mut storage).expect("compresses");
+    storage.as_ref().to_vec()
+}
+
+fn time<F: FnMut() -> usize>(mut f: F) -> (usize, Duration) {
+    let start = Instant::now();
+    let mut last = f();
+    for _ in 1..ITERATIONS {
+        last = black_box(f());
+    }
+    (last, start.elapsed() / ITERATIONS)
+}
+
+fn main() {
+    let threshold = env_or("CHAT_WS_COMPRESSION_THRESHOLD", 256);
+    let level = env_or("CHAT_WS_COMPRESSION_LEVEL", 6);
+    println!("threshold {} bytes, level {}, {} recipients per broadcast\n", threshold, level, RECIPIENTS);
+
+    for encoding in [Encoding::Json, Encoding::MsgPack] {
+        println!("{:?}", encoding);
+        println!(
+            "{:<18} {:>9} {:>9} {:>11} {:>7} {:>10} {:>12}",
+            "frame", "plain B", "alone B", "in room B", "ratio", "encode", "compress"
+        );
+
+        // One connection that has seen the rest of the workload, as in a
+        // busy room or a history backfill.
+        let mut connection = PerMessageDeflate::new(threshold, level, false);
+        // Resets after every message, so each one is compressed on its own.
+        let mut alone = PerMessageDeflate::new(threshold, level, true);
+        let (mut plain_total, mut compressed_total, mut compress_time) = (0, 0, Duration::ZERO);
+        for (name, frame) in workload() {
+            let outbound = frame.encode(encoding);
+            let (plain_len, encode_time) = time(|| match frame.encode(encoding) {
+                Outbound::Text(text) => text.len(),
+                Outbound::Binary(data) => data.len(),
+            });
+            let (alone_len, alone_time) = time(|| send(&mut alone, &outbound).len());
+            let in_room_len = send(&mut connection, &outbound).len();
+
+            plain_total += plain_len * RECIPIENTS;
+            compressed_total += in_room_len * RECIPIENTS;
+            compress_time += alone_time * RECIPIENTS as u32;
+            println!(
+                "{:<18} {:>9} {:>9} {:>11} {:>6.2}x {:>10?} {:>12?}",
+                name,
+                plain_len,
+                alone_len,
+                in_room_len,
+                plain_len as f64 / in_room_len as f64,
+                encode_time,
+                alone_time
+            );
+        }
+        println!(
+            "broadcast to {} clients: {} B plain, {} B compressed ({:.0}% saved), {:?} compressing\n",
+            RECIPIENTS,
+            plain_total,
+            compressed_total,
+            100.0 * (1.0 - compressed_total as f64 / plain_total as f64),
+            compress_time
+        );
+    }
+}
@@ -0,0 +1,806 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use log::error;
+use rusqlite::params;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::auth::{self, json_message, Identity, ResponseMessage};
+use crate::chat::{self, Clients, LOBBY_ROOM};
+use crate::config::CONFIG;
+use crate::db::Db;
+use crate::handling_files;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+/// Largest request body on routes that only take a small JSON object.
+pub const MAX_JSON_BODY: u64 = 64 * 1024;
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RoomInfo {
+    pub id: String,
+    pub online: usize,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UserInfo {
+    pub username: String,
+    pub online: bool,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub room: String,
+    /// "message" or "file".
+    pub kind: String,
+    pub sender: String,
+    pub content: String,
+    pub filename: Option<String>,
+    pub file_id: Option<String>,
+    pub created_at: i64,
+    /// When the file of a "file" message was deleted, if it has been.
+    pub deleted_at: Option<i64>,
+    /// Who deleted it, `None` if it expired.
+    pub deleted_by: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct FileInfo {
+    pub file_id: String,
+    pub filename: String,
+    pub sender: String,
+    pub message_id: i64,
+    pub created_at: i64,
+    pub size: u64,
+    /// Sniffed from the content.
+    pub mime: String,
+    /// Served from `/thumbnails/{id}` if the file is an image.
+    pub thumbnail_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Created {
+    pub id: i64,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UploadedFile {
+    pub file_id: String,
+    pub message_id: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PostMessage {
+    pub content: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct UploadFile {
+    pub filename: String,
+    /// Base64 encoded file contents.
+    pub content: String,
+    /// Whether to remove EXIF and XMP metadata from an image. Defaults to
+    /// the server's setting.
+    #[serde(default)]
+    pub strip_metadata: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageQuery {
+    /// Only return items older than this message id.
+    pub before: Option<i64>,
+    /// Page size, at most 200.
+    pub limit: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FileQuery {
+    /// Only return files older than this message id.
+    pub before: Option<i64>,
+    /// Page size, at most 200.
+    pub limit: Option<u32>,
+    /// Only files shared by this user or bot.
+    pub uploader: Option<String>,
+    /// A MIME type such as `application/pdf`, or a top-level type such as
+    /// `image` (or `image/*`) for all of its subtypes.
+    #[serde(rename = "type")]
+    pub mime: Option<String>,
+    /// Only files shared at or after this Unix time.
+    pub since: Option<i64>,
+    /// Only files shared before this Unix time.
+    pub until: Option<i64>,
+}
+
+impl FileQuery {
+    fn page(&self) -> PageQuery {
+        PageQuery {
+            before: self.before,
+            limit: self.limit,
+        }
+    }
+
+    /// `LIKE` pattern for the `type` filter, with `\` as the escape
+    /// character so `%` and `_` in the type match themselves.
+    fn mime_pattern(&self) -> Option<String> {
+        let mime = self.mime.as_deref()?.trim().to_ascii_lowercase();
+        let top_level = mime.strip_suffix("/*").unwrap_or(&mime);
+        let escaped = top_level.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        Some(match top_level.contains('/') {
+            true => escaped,
+            false => format!("{}/%", escaped),
+        })
+    }
+}
+
+impl PageQuery {
+    fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    fn before(&self) -> i64 {
+        self.before.unwrap_or(i64::MAX)
+    }
+}
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session_cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("session_token"))),
+        );
+        components.add_security_scheme(
+            "bot_token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Chat API", version = "1", description = "REST access to rooms, history, users and files."),
+    paths(
+        handle_list_rooms,
+        handle_history,
+        handle_post_message,
+        handle_list_users,
+        handle_list_files,
+        handle_upload_file,
+        handle_delete_file,
+    ),
+    modifiers(&SecurityAddon),
+    security(("session_cookie" = []), ("bot_token" = []))
+)]
+pub struct ApiDoc;
+
+/// Routes under `/api/v1`.
+/// A JSON request body of at most `MAX_JSON_BODY` bytes.
+pub fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Copy {
+    warp::body::content_length_limit(MAX_JSON_BODY).and(warp::body::json())
+}
+
+pub fn routes(
+    clients: Clients,
+    db: Db,
+) -> impl Filter<Extract = (Box<dyn warp::Reply>,), Error = warp::Rejection> + Clone {
+    let clients = warp::any().map(move || clients.clone());
+    let identity = warp::cookie::optional("session_token")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth::with_db(db.clone()));
+
+    let openapi = warp::path!("api" / "v1" / "openapi.json")
+        .and(warp::get())
+        .map(|| Box::new(warp::reply::json(&ApiDoc::openapi())) as Box<dyn warp::Reply>);
+
+    let list_rooms = warp::path!("api" / "v1" / "rooms")
+        .and(warp::get())
+        .and(identity.clone())
+        .and(clients.clone())
+        .and_then(handle_list_rooms);
+
+    let history = warp::path!("api" / "v1" / "rooms" / String / "messages")
+        .and(warp::get())
+        .and(warp::query::<PageQuery>())
+        .and(identity.clone())
+        .and_then(handle_history);
+
+    let post_message = warp::path!("api" / "v1" / "rooms" / String / "messages")
+        .and(warp::post())
+        .and(json_body())
+        .and(identity.clone())
+        .and(clients.clone())
+        .and_then(handle_post_message);
+
+    let list_users = warp::path!("api" / "v1" / "users")
+        .and(warp::get())
+        .and(identity.clone())
+        .and(clients.clone())
+        .and_then(handle_list_users);
+
+    let list_files = warp::path!("api" / "v1" / "rooms" / String / "files")
+        .and(warp::get())
+        .and(warp::query::<FileQuery>())
+        .and(identity.clone())
+        .and_then(handle_list_files);
+
+    // The file is base64 encoded, a third larger than on disk, and the
+    // rest of the object is small.
+    let upload_file = warp::path!("api" / "v1" / "rooms" / String / "files")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(CONFIG.max_file_size * 4 / 3 + MAX_JSON_BODY))
+        .and(warp::body::json())
+        .and(identity.clone())
+        .and(clients.clone())
+        .and_then(handle_upload_file);
+
+    let delete_file = warp::path!("api" / "v1" / "rooms" / String / "files" / String)
+        .and(warp::delete())
+        .and(identity)
+        .and(clients)
+        .and_then(handle_delete_file);
+
+    openapi
+        .or(list_rooms)
+        .unify()
+        .or(history)
+        .unify()
+        .or(post_message)
+        .unify()
+        .or(list_users)
+        .unify()
+        .or(list_files)
+        .unify()
+        .or(upload_file)
+        .unify()
+        .or(delete_file)
+        .unify()
+}
+
+/// Resolves the caller and checks that it may use `room`, producing the
+/// reply to send back when it may not.
+async fn authorize_room(
+    db: &Db,
+    session_token: Option<&str>,
+    authorization: Option<&str>,
+    room: &str,
+) -> Result<Identity, Box<dyn warp::Reply>> {
+    let identity = match auth::authenticate(db, session_token, authorization).await {
+        Some(identity) => identity,
+        None => return Err(json_message("Not authenticated.", StatusCode::UNAUTHORIZED)),
+    };
+    if !chat::room_exists(room) {
+        return Err(json_message("Room not found.", StatusCode::NOT_FOUND));
+    }
+    if !identity.can_access(room) {
+        return Err(json_message("Not allowed to access this room.", StatusCode::FORBIDDEN));
+    }
+    Ok(identity)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/rooms",
+    responses(
+        (status = 200, description = "Rooms the caller can access", body = [RoomInfo]),
+        (status = 401, body = ResponseMessage),
+    )
+)]
+pub async fn handle_list_rooms(
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+    clients: Clients,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let identity = match auth::authenticate(&db, session_token.as_deref(), authorization.as_deref()).await {
+        Some(identity) => identity,
+        None => return Ok(json_message("Not authenticated.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let online = clients.lock().unwrap().len();
+    let rooms: Vec<RoomInfo> = [LOBBY_ROOM]
+        .into_iter()
+        .filter(|room| identity.can_access(room))
+        .map(|room| RoomInfo {
+            id: room.to_string(),
+            online,
+        })
+        .collect();
+    Ok(Box::new(warp::reply::json(&rooms)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/rooms/{id}/messages",
+    params(("id" = String, Path, description = "Room id"), PageQuery),
+    responses(
+        (status = 200, description = "Messages, newest first", body = [StoredMessage]),
+        (status = 401, body = ResponseMessage),
+        (status = 403, body = ResponseMessage),
+        (status = 404, body = ResponseMessage),
+    )
+)]
+pub async fn handle_history(
+    room: String,
+    page: PageQuery,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if let Err(reply) = authorize_room(&db, session_token.as_deref(), authorization.as_deref(), &room).await {
+        return Ok(reply);
+    }
+
+    let (room_id, before, limit) = (room.clone(), page.before(), page.limit());
+    let result = db
+        .run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, room, kind, sender, content, filename, file_id, created_at, deleted_at, deleted_by
+                 FROM messages WHERE room = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+            )?;
+            let messages = stmt.query_map(params![room_id, before, limit], |row| {
+                Ok(StoredMessage {
+                    id: row.get(0)?,
+                    room: row.get(1)?,
+                    kind: row.get(2)?,
+                    sender: row.get(3)?,
+                    content: row.get(4)?,
+                    filename: row.get(5)?,
+                    file_id: row.get(6)?,
+                    created_at: row.get(7)?,
+                    deleted_at: row.get(8)?,
+                    deleted_by: row.get(9)?,
+                })
+            })?;
+            messages.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await;
+
+    match result {
+        Ok(messages) => Ok(Box::new(warp::reply::json(&messages))),
+        Err(e) => {
+            error!("Failed to load history for room {}: {:?}", room, e);
+            Ok(json_message("Failed to load history.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/rooms/{id}/messages",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = PostMessage,
+    responses(
+        (status = 201, description = "Message stored and broadcast", body = Created),
+        (status = 400, body = ResponseMessage),
+        (status = 401, body = ResponseMessage),
+        (status = 403, body = ResponseMessage),
+        (status = 404, body = ResponseMessage),        (status = 413, description = "Body larger than 64KB"),
+    )
+)]
+pub async fn handle_post_message(
+    room: String,
+    request: PostMessage,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+    clients: Clients,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let identity = match authorize_room(&db, session_token.as_deref(), authorization.as_deref(), &room).await {
+        Ok(identity) => identity,
+        Err(reply) => return Ok(reply),
+    };
+    if request.content.trim().is_empty() {
+        return Ok(json_message("Message cannot be empty.", StatusCode::BAD_REQUEST));
+    }
+
+    match chat::publish_message(&clients, &db, identity.name(), request.content).await {
+        Some(id) => Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&Created { id }),
+            StatusCode::CREATED,
+        ))),
+        None => Ok(json_message("Message was broadcast but not stored.", StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users",
+    responses(
+        (status = 200, description = "Registered users and whether they are connected", body = [UserInfo]),
+        (status = 401, body = ResponseMessage),
+    )
+)]
+pub async fn handle_list_users(
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+    clients: Clients,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if auth::authenticate(&db, session_token.as_deref(), authorization.as_deref()).await.is_none() {
+        return Ok(json_message("Not authenticated.", StatusCode::UNAUTHORIZED));
+    }
+
+    let online: HashSet<String> = clients
+        .lock()
+        .unwrap()
+        .values()
+        .map(|client| client.username.clone())
+        .collect();
+
+    let result = db
+        .run(|conn| {
+            conn.prepare("SELECT username FROM users ORDER BY username")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await;
+
+    match result {
+        Ok(usernames) => {
+            let users: Vec<UserInfo> = usernames
+                .into_iter()
+                .map(|username| UserInfo {
+                    online: online.contains(&username),
+                    username,
+                })
+                .collect();
+            Ok(Box::new(warp::reply::json(&users)))
+        }
+        Err(e) => {
+            error!("Failed to list users: {:?}", e);
+            Ok(json_message("Failed to list users.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/rooms/{id}/files",
+    params(("id" = String, Path, description = "Room id"), FileQuery),
+    responses(
+        (
+            status = 200,
+            description = "Files shared in the room, newest first",
+            body = [FileInfo],
+            headers(
+                ("X-Total-Count" = u64, description = "Files matching the filters, on every page"),
+                ("X-Total-Size" = u64, description = "Their combined size in bytes"),
+            ),
+        ),
+        (status = 401, body = ResponseMessage),
+        (status = 403, body = ResponseMessage),
+        (status = 404, body = ResponseMessage),
+    )
+)]
+pub async fn handle_list_files(
+    room: String,
+    query: FileQuery,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if let Err(reply) = authorize_room(&db, session_token.as_deref(), authorization.as_deref(), &room).await {
+        return Ok(reply);
+    }
+
+    // Files that were deleted are left out, as are old messages whose file
+    // is gone.
+    const MATCHING: &str = "FROM messages m JOIN files f ON f.id = m.file_id
+        LEFT JOIN images i ON i.sha256 = f.sha256
+        WHERE m.room = ?1 AND m.kind = 'file' AND m.deleted_at IS NULL
+        AND (?2 IS NULL OR m.sender = ?2)
+        AND (?3 IS NULL OR f.mime LIKE ?3 ESCAPE '\\')
+        AND (?4 IS NULL OR m.created_at >= ?4)
+        AND (?5 IS NULL OR m.created_at < ?5)";
+    let page = query.page();
+    let mime = query.mime_pattern();
+
+    let (room_id, before, limit) = (room.clone(), page.before(), page.limit());
+    let result = db
+        .run(move |conn| {
+            let totals = conn.query_row(
+                &format!("SELECT COUNT(*), COALESCE(SUM(f.size), 0) {}", MATCHING),
+                params![room_id, query.uploader, mime, query.since, query.until],
+                |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)),
+            )?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT m.file_id, m.filename, m.sender, m.id, m.created_at, f.size, f.mime, i.thumbnail_id
+                 {} AND m.id < ?6 ORDER BY m.id DESC LIMIT ?7",
+                MATCHING
+            ))?;
+            let files = stmt.query_map(
+                params![room_id, query.uploader, mime, query.since, query.until, before, limit],
+                |row| {
+                    Ok(FileInfo {
+                        file_id: row.get(0)?,
+                        filename: row.get(1)?,
+                        sender: row.get(2)?,
+                        message_id: row.get(3)?,
+                        created_at: row.get(4)?,
+                        size: row.get(5)?,
+                        mime: row.get(6)?,
+                        thumbnail_id: row.get(7)?,
+                    })
+                },
+            )?;
+            Ok((totals, files.collect::<rusqlite::Result<Vec<_>>>()?))
+        })
+        .await;
+
+    match result {
+        Ok(((count, size), files)) => {
+            let reply = warp::reply::json(&files);
+            let reply = warp::reply::with_header(reply, "X-Total-Count", count.to_string());
+            Ok(Box::new(warp::reply::with_header(reply, "X-Total-Size", size.to_string())))
+        }
+        Err(e) => {
+            error!("Failed to list files for room {}: {:?}", room, e);
+            Ok(json_message("Failed to list files.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/rooms/{id}/files",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = UploadFile,
+    responses(
+        (status = 201, description = "File stored and announced", body = UploadedFile),
+        (status = 400, body = ResponseMessage),
+        (status = 401, body = ResponseMessage),
+        (status = 403, body = ResponseMessage),
+        (status = 404, body = ResponseMessage),
+        (status = 413, description = "Body larger than the largest file allowed, base64 encoded"),
+    )
+)]
+pub async fn handle_upload_file(
+    room: String,
+    request: UploadFile,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+    clients: Clients,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    use base64::Engine;
+
+    let identity = match authorize_room(&db, session_token.as_deref(), authorization.as_deref(), &room).await {
+        Ok(identity) => identity,
+        Err(reply) => return Ok(reply),
+    };
+    if request.filename.trim().is_empty() {
+        return Ok(json_message("Filename cannot be empty.", StatusCode::BAD_REQUEST));
+    }
+
+    let data = match base64::engine::general_purpose::STANDARD.decode(&request.content) {
+        Ok(data) => data,
+        Err(_) => return Ok(json_message("File content must be base64.", StatusCode::BAD_REQUEST)),
+    };
+
+    let file_id = match handling_files::save_upload(&db, identity.name(), &request.filename, &data, request.strip_metadata).await {
+        Ok(file_id) => file_id,
+        Err(e) => {
+            error!("Failed to save file {}: {}", request.filename, e);
+            chat::notify_quarantined(&clients, identity.name(), &request.filename, &e);
+            return Ok(e.to_reply());
+        }
+    };
+
+    let message_id =
+        chat::publish_file(&clients, &db, identity.name(), request.filename, file_id.clone()).await;
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&UploadedFile { file_id, message_id }),
+        StatusCode::CREATED,
+    )))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/rooms/{id}/files/{file_id}",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("file_id" = String, Path, description = "File id"),
+    ),
+    responses(
+        (status = 200, description = "File deleted and its message updated", body = ResponseMessage),
+        (status = 401, body = ResponseMessage),
+        (status = 403, description = "Neither the uploader nor a moderator", body = ResponseMessage),
+        (status = 404, body = ResponseMessage),
+    )
+)]
+pub async fn handle_delete_file(
+    room: String,
+    file_id: String,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+    clients: Clients,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let identity = match authorize_room(&db, session_token.as_deref(), authorization.as_deref(), &room).await {
+        Ok(identity) => identity,
+        Err(reply) => return Ok(reply),
+    };
+    let file = match handling_files::find_file(&db, &file_id).await {
+        Some(file) if file.room == room => file,
+        _ => return Ok(json_message("File not found.", StatusCode::NOT_FOUND)),
+    };
+    if file.uploader.as_deref() != Some(identity.name()) && !identity.is_moderator() {
+        return Ok(json_message("Only the uploader or a moderator can delete this file.", StatusCode::FORBIDDEN));
+    }
+
+    match chat::publish_file_deletion(&clients, &db, &file_id, Some(identity.name())).await {
+        Ok(true) => Ok(json_message("File deleted.", StatusCode::OK)),
+        Ok(false) => Ok(json_message("File not found.", StatusCode::NOT_FOUND)),
+        Err(e) => {
+            error!("Failed to delete file {}: {:?}", file_id, e);
+            Ok(json_message("Failed to delete file.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::db;
+
+    async fn post(path: &str, content_length: u64, body: &str) -> StatusCode {
+        let (_dir, db) = db::open_temp().await;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("content-type", "application/json")
+            .body(body)
+            // After the body, which sets it to the real length.
+            .header("content-length", content_length)
+            .reply(&routes(clients, db))
+            .await;
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn caps_json_bodies() {
+        let content = "x".repeat(MAX_JSON_BODY as usize);
+        let body = format!("{{\"content\":\"{}\"}}", content);
+        let status = post("/api/v1/rooms/lobby/messages", body.len() as u64, &body).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Within the limit, the request gets as far as authentication.
+        let body = r#"{"content":"hello"}"#;
+        let status = post("/api/v1/rooms/lobby/messages", body.len() as u64, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn caps_uploads_at_the_encoded_file_size() {
+        let body = format!(r#"{{"filename":"a.bin","content":"{}"}}"#, "A".repeat(1 << 20));
+        let status = post("/api/v1/rooms/lobby/files", body.len() as u64, &body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let limit = CONFIG.max_file_size * 4 / 3 + MAX_JSON_BODY;
+        let status = post("/api/v1/rooms/lobby/files", limit + 1, "{}").await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    fn file_query(mime: Option<&str>) -> FileQuery {
+        FileQuery {
+            before: None,
+            limit: None,
+            uploader: None,
+            mime: mime.map(String::from),
+            since: None,
+            until: None,
+        }
+    }
+
+    #[test]
+    fn mime_filter_escapes_like_wildcards() {
+        let pattern = |mime| file_query(Some(mime)).mime_pattern().unwrap();
+        assert_eq!(pattern("application/pdf"), "application/pdf");
+        assert_eq!(pattern(" Image/PNG "), "image/png");
+        assert_eq!(pattern("image"), "image/%");
+        assert_eq!(pattern("image/*"), "image/%");
+        assert_eq!(pattern("%"), "\\%/%");
+        assert_eq!(pattern("application/vnd_ms%"), "application/vnd\\_ms\\%");
+        assert_eq!(pattern("a\\b"), "a\\\\b/%");
+        assert_eq!(file_query(None).mime_pattern(), None);
+    }
+
+    /// Shares files in the lobby as `(name, mime, size)`, oldest first, and
+    /// returns their message ids.
+    async fn share_files(db: &Db, files: &[(&'static str, &'static str, u64)]) -> Vec<i64> {
+        let files = files.to_vec();
+        db.run(move |conn| {
+            let mut ids = Vec::new();
+            for (name, mime, size) in files {
+                conn.execute(
+                    "INSERT INTO files (id, original_name, size, mime, uploader, room, sha256, created_at)
+                     VALUES (?1, ?1, ?2, ?3, 'alice', 'lobby', ?1, 0)",
+                    params![name, size, mime],
+                )?;
+                conn.execute(
+                    "INSERT INTO messages (room, kind, sender, filename, file_id, created_at)
+                     VALUES ('lobby', 'file', 'alice', ?1, ?1, 0)",
+                    params![name],
+                )?;
+                ids.push(conn.last_insert_rowid());
+            }
+            Ok(ids)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Lists lobby files as a logged in user: the file names, and the
+    /// `X-Total-Count` and `X-Total-Size` headers.
+    async fn list(db: &Db, query: FileQuery) -> (Vec<String>, String, String) {
+        let token = uuid::Uuid::new_v4().to_string();
+        crate::shared::SESSIONS.lock().unwrap().insert(token.clone(), "alice".into());
+        let reply = handle_list_files("lobby".into(), query, Some(token), None, db.clone()).await.unwrap();
+        let response = warp::Reply::into_response(reply);
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = |name| response.headers()[name].to_str().unwrap().to_string();
+        let (count, size) = (header("x-total-count"), header("x-total-size"));
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let files: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let names = files.iter().map(|file| file["filename"].as_str().unwrap().to_string()).collect();
+        (names, count, size)
+    }
+
+    #[tokio::test]
+    async fn lists_files_in_pages_with_totals() {
+        let (_dir, db) = db::open_temp().await;
+        let ids = share_files(
+            &db,
+            &[
+                ("a.png", "image/png", 100),
+                ("b.pdf", "application/pdf", 1000),
+                ("c.jpg", "image/jpeg", 200),
+                ("d.png", "image/png", 300),
+                ("e.txt", "text/plain", 10),
+            ],
+        )
+        .await;
+
+        let (names, count, size) = list(&db, file_query(None)).await;
+        assert_eq!(names, ["e.txt", "d.png", "c.jpg", "b.pdf", "a.png"]);
+        assert_eq!((count.as_str(), size.as_str()), ("5", "1610"));
+
+        // Totals cover every page of the filtered files, not just this one.
+        let mut query = file_query(Some("image"));
+        query.limit = Some(2);
+        let (names, count, size) = list(&db, query).await;
+        assert_eq!(names, ["d.png", "c.jpg"]);
+        assert_eq!((count.as_str(), size.as_str()), ("3", "600"));
+
+        let mut query = file_query(Some("image/*"));
+        query.limit = Some(2);
+        query.before = Some(ids[2]);
+        let (names, count, _) = list(&db, query).await;
+        assert_eq!(names, ["a.png"]);
+        assert_eq!(count, "3");
+
+        let mut query = file_query(Some("image/png"));
+        query.before = Some(ids[3]);
+        assert_eq!(list(&db, query).await.0, ["a.png"]);
+
+        // Wildcards in the filter are taken literally.
+        for mime in ["%", "image/_ng", "%/%"] {
+            let (names, count, size) = list(&db, file_query(Some(mime))).await;
+            assert!(names.is_empty(), "{}: {:?}", mime, names);
+            assert_eq!((count.as_str(), size.as_str()), ("0", "0"));
+        }
+    }
+}
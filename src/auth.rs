@@ -1,12 +1,15 @@
 use warp::Filter;
-use std::sync::{Arc, Mutex};
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
 use serde::{Deserialize, Serialize};
 use warp::http::StatusCode;
 use log::{info, error};
+use utoipa::ToSchema;
 use uuid::Uuid;
-use warp::Reply;
 
+use crate::bots::{self, BotIdentity};
+use crate::chat;
+use crate::config::CONFIG;
+use crate::db::Db;
 use crate::shared::SESSIONS;
 
 #[derive(Deserialize, Debug)]
@@ -21,20 +24,20 @@ pub struct UserLogin {
     pub password: String,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, ToSchema)]
 pub struct ResponseMessage {
     pub message: String,
 }
 
 pub fn with_db(
-    db: Arc<Mutex<Connection>>,
-) -> impl Filter<Extract = (Arc<Mutex<Connection>>,), Error = std::convert::Infallible> + Clone {
+    db: Db,
+) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
 
 pub async fn handle_register(
     user: UserRegister,
-    db: Arc<Mutex<Connection>>,
+    db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     use bcrypt::{hash, DEFAULT_COST};
 
@@ -50,7 +53,12 @@ pub async fn handle_register(
         ));
     }
 
-    let hashed_password = match hash(&user.password, DEFAULT_COST) {
+    // bcrypt is slow on purpose; keep it off the async workers.
+    let password = user.password.clone();
+    let hashed_password = match tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST))
+        .await
+        .expect("Password hashing panicked")
+    {
         Ok(h) => h,
         Err(e) => {
             error!("Password hashing failed: {:?}", e);
@@ -64,27 +72,17 @@ pub async fn handle_register(
         }
     };
 
-    let conn = match db.lock() {
-        Ok(lock) => lock,
-        Err(e) => {
-            error!("Failed to acquire DB lock: {:?}", e);
+    let username = user.username.clone();
+    let result = db.run(move |conn| insert_user(conn, &username, &hashed_password)).await;
+
+    match result {
+        Ok(false) => {
             let json = warp::reply::json(&ResponseMessage {
-                message: "Internal server error.".into(),
+                message: "Username already exists.".into(),
             });
-            return Ok(warp::reply::with_status(
-                json,
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST))
         }
-    };
-
-    let result = conn.execute(
-        "INSERT INTO users (username, password) VALUES (?1, ?2)",
-        params![user.username, hashed_password],
-    );
-
-    match result {
-        Ok(_) => {
+        Ok(true) => {
             info!("User '{}' registered successfully", user.username);
             let json = warp::reply::json(&ResponseMessage {
                 message: "User registered successfully.".into(),
@@ -104,9 +102,23 @@ pub async fn handle_register(
     }
 }
 
+/// Adds a user, or returns `false` if a user or bot already has the name.
+pub fn insert_user(conn: &mut Connection, username: &str, hashed_password: &str) -> rusqlite::Result<bool> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if bots::name_taken(&tx, username)? {
+        return Ok(false);
+    }
+    tx.execute(
+        "INSERT INTO users (username, password) VALUES (?1, ?2)",
+        params![username, hashed_password],
+    )?;
+    tx.commit()?;
+    Ok(true)
+}
+
 pub async fn handle_login(
     user: UserLogin,
-    db: Arc<Mutex<Connection>>,
+    db: Db,
 ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
     use warp::hyper::header::SET_COOKIE;
     use bcrypt::verify;
@@ -123,12 +135,23 @@ pub async fn handle_login(
         )));
     }
 
-    let conn = match db.lock() {
-        Ok(lock) => lock,
+    let username = user.username.clone();
+    let stored_password = match db
+        .run(move |conn| {
+            conn.query_row(
+                "SELECT password FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+        })
+        .await
+    {
+        Ok(p) => p,
         Err(e) => {
-            error!("Failed to acquire DB lock: {:?}", e);
+            error!("Failed to look up user: {:?}", e);
             let json = warp::reply::json(&ResponseMessage {
-                message: "Internal server error.".into(),
+                message: "Error retrieving password.".into(),
             });
             return Ok(Box::new(warp::reply::with_status(
                 json,
@@ -137,49 +160,12 @@ pub async fn handle_login(
         }
     };
 
-    let mut stmt = match conn.prepare("SELECT password FROM users WHERE username = ?1") {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to prepare statement: {:?}", e);
-            let json = warp::reply::json(&ResponseMessage {
-                message: "User not found.".into(),
-            });
-            return Ok(Box::new(warp::reply::with_status(
-                json,
-                StatusCode::UNAUTHORIZED,
-            )));
-        }
-    };
-
-    let mut rows = match stmt.query(params![user.username]) {
-        Ok(r) => r,
-        Err(e) => {
-            error!("Failed to execute query: {:?}", e);
-            let json = warp::reply::json(&ResponseMessage {
-                message: "User not found.".into(),
-            });
-            return Ok(Box::new(warp::reply::with_status(
-                json,
-                StatusCode::UNAUTHORIZED,
-            )));
-        }
-    };
-
-    if let Ok(Some(row_result)) = rows.next() {
-        let stored_password: String = match row_result.get(0) {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Failed to get password from row: {:?}", e);
-                let json = warp::reply::json(&ResponseMessage {
-                    message: "Error retrieving password.".into(),
-                });
-                return Ok(Box::new(warp::reply::with_status(
-                    json,
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )));
-            }
-        };
-        if verify(&user.password, &stored_password).unwrap_or(false) {
+    if let Some(stored_password) = stored_password {
+        let password = user.password.clone();
+        let valid = tokio::task::spawn_blocking(move || verify(password, &stored_password).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        if valid {
             info!("User '{}' logged in successfully", user.username);
 
             // Generate a session token
@@ -217,3 +203,109 @@ pub async fn handle_login(
         StatusCode::UNAUTHORIZED,
     )))
 }
+
+pub fn session_user(session_token: Option<&str>) -> Option<String> {
+    let token = session_token?;
+    SESSIONS.lock().unwrap().get(token).cloned()
+}
+
+pub fn json_message(message: &str, status: StatusCode) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&ResponseMessage {
+            message: message.into(),
+        }),
+        status,
+    ))
+}
+
+/// Who is making an HTTP API request: a logged in user or a bot.
+#[derive(Debug, Clone)]
+pub enum Identity {
+    User(String),
+    Bot(BotIdentity),
+}
+
+impl Identity {
+    pub fn name(&self) -> &str {
+        match self {
+            Identity::User(username) => username,
+            Identity::Bot(bot) => &bot.name,
+        }
+    }
+
+    /// Users can use every room, just like on the WebSocket; bots are
+    /// limited to the rooms their token is scoped to.
+    pub fn can_access(&self, room: &str) -> bool {
+        chat::room_exists(room)
+            && match self {
+                Identity::User(_) => true,
+                Identity::Bot(bot) => bot.can_post_to(room),
+            }
+    }
+
+    /// Moderators are users listed in `CHAT_MODERATORS`; bots never are.
+    pub fn is_moderator(&self) -> bool {
+        match self {
+            Identity::User(username) => CONFIG.moderators.contains(username),
+            Identity::Bot(_) => false,
+        }
+    }
+}
+
+/// Accepts either the session cookie or an `Authorization: Bearer` bot token.
+pub async fn authenticate(
+    db: &Db,
+    session_token: Option<&str>,
+    authorization: Option<&str>,
+) -> Option<Identity> {
+    if let Some(username) = session_user(session_token) {
+        return Some(Identity::User(username));
+    }
+    bots::authenticate(db, authorization).await.map(Identity::Bot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bot(rooms: &[&str]) -> Identity {
+        Identity::Bot(BotIdentity {
+            name: "helper".into(),
+            rooms: rooms.iter().map(|room| room.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn bots_access_the_rooms_they_are_scoped_to() {
+        let user = Identity::User("alice".into());
+        assert!(user.can_access(chat::LOBBY_ROOM));
+        assert!(!user.can_access("elsewhere"));
+
+        // `*` is every room that exists, not any name at all.
+        assert!(bot(&["*"]).can_access(chat::LOBBY_ROOM));
+        assert!(!bot(&["*"]).can_access("elsewhere"));
+
+        assert!(bot(&[chat::LOBBY_ROOM]).can_access(chat::LOBBY_ROOM));
+        assert!(bot(&["elsewhere", chat::LOBBY_ROOM]).can_access(chat::LOBBY_ROOM));
+        assert!(!bot(&["elsewhere"]).can_access(chat::LOBBY_ROOM));
+        assert!(!bot(&["elsewhere"]).can_access("elsewhere"));
+        assert!(!bot(&[]).can_access(chat::LOBBY_ROOM));
+    }
+
+    #[tokio::test]
+    async fn users_cannot_take_bot_names() {
+        let (_dir, db) = crate::db::open_temp().await;
+        db.run(|conn| {
+            assert!(insert_user(conn, "alice", "hash")?);
+            assert!(!insert_user(conn, "alice", "hash")?);
+            conn.execute(
+                "INSERT INTO bots (name, created_by, created_at) VALUES ('helper', 'alice', 0)",
+                [],
+            )?;
+            assert!(!insert_user(conn, "helper", "hash")?);
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+}
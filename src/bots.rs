@@ -0,0 +1,393 @@
+use log::{error, info};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::http::StatusCode;
+
+use crate::auth::{json_message, session_user};
+use crate::chat;
+use crate::db::{self, Db};
+
+const ALL_ROOMS: &str = "*";
+
+#[derive(Deserialize, Debug)]
+pub struct CreateBot {
+    pub name: String,
+    pub rooms: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateToken {
+    pub rooms: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IssuedToken {
+    pub bot_id: i64,
+    pub name: String,
+    pub token_id: i64,
+    /// Only ever returned here, the server keeps a hash.
+    pub token: String,
+    pub rooms: Vec<String>,
+}
+
+/// A bot authenticated by its API token.
+#[derive(Debug, Clone)]
+pub struct BotIdentity {
+    pub name: String,
+    pub rooms: Vec<String>,
+}
+
+impl BotIdentity {
+    pub fn can_post_to(&self, room: &str) -> bool {
+        self.rooms.iter().any(|r| r == ALL_ROOMS || r == room)
+    }
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("bot_{}", hex::encode(bytes))
+}
+
+// Tokens are long and random, so a fast unsalted hash is enough to keep a
+// leaked database from handing out working credentials.
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether a user or a bot already goes by `name`. Bots post under their
+/// own name, so the two share one namespace. Call this in an immediate
+/// transaction that also inserts the name, so two requests cannot both
+/// find it free.
+pub fn name_taken(conn: &Connection, name: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1)
+             OR EXISTS(SELECT 1 FROM bots WHERE name = ?1)",
+        params![name],
+        |row| row.get(0),
+    )
+}
+
+fn valid_scope(rooms: &[String]) -> bool {
+    !rooms.is_empty() && rooms.iter().all(|r| r == ALL_ROOMS || chat::room_exists(r))
+}
+
+/// Resolves an `Authorization: Bearer <token>` header to the bot it
+/// belongs to.
+pub async fn authenticate(db: &Db, authorization: Option<&str>) -> Option<BotIdentity> {
+    let token = authorization?.strip_prefix("Bearer ")?.trim();
+    let token_hash = hash_token(token);
+
+    let result = db
+        .run(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT t.id, b.name, t.rooms FROM api_tokens t JOIN bots b ON b.id = t.bot_id
+                     WHERE t.token_hash = ?1",
+                    params![token_hash],
+                    |row| {
+                        let token_id: i64 = row.get(0)?;
+                        let name: String = row.get(1)?;
+                        let rooms: String = row.get(2)?;
+                        Ok((token_id, name, rooms))
+                    },
+                )
+                .optional()?;
+            if let Some((token_id, _, _)) = &found {
+                let _ = conn.execute(
+                    "UPDATE api_tokens SET last_used_at = ?1 WHERE id = ?2",
+                    params![db::now(), token_id],
+                );
+            }
+            Ok(found)
+        })
+        .await;
+
+    match result {
+        Ok(Some((_, name, rooms))) => {
+            Some(BotIdentity {
+                name,
+                rooms: rooms.split(',').map(String::from).collect(),
+            })
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to look up API token: {:?}", e);
+            None
+        }
+    }
+}
+
+fn insert_token(conn: &Connection, bot_id: i64, rooms: &[String]) -> rusqlite::Result<(i64, String)> {
+    let token = generate_token();
+    conn.execute(
+        "INSERT INTO api_tokens (bot_id, token_hash, rooms, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![bot_id, hash_token(&token), rooms.join(","), db::now()],
+    )?;
+    Ok((conn.last_insert_rowid(), token))
+}
+
+/// Creates a bot with a first token, returning their ids and the token, or
+/// `None` if the name is taken.
+fn insert_bot(
+    conn: &mut Connection,
+    name: &str,
+    creator: &str,
+    rooms: &[String],
+) -> rusqlite::Result<Option<(i64, i64, String)>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if name_taken(&tx, name)? {
+        return Ok(None);
+    }
+
+    tx.execute(
+        "INSERT INTO bots (name, created_by, created_at) VALUES (?1, ?2, ?3)",
+        params![name, creator, db::now()],
+    )?;
+    let bot_id = tx.last_insert_rowid();
+    let (token_id, token) = insert_token(&tx, bot_id, rooms)?;
+    tx.commit()?;
+    Ok(Some((bot_id, token_id, token)))
+}
+
+pub async fn handle_create_bot(
+    request: CreateBot,
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return Ok(json_message("Bot name cannot be empty.", StatusCode::BAD_REQUEST));
+    }
+    if !valid_scope(&request.rooms) {
+        return Ok(json_message("Token scope must list known rooms.", StatusCode::BAD_REQUEST));
+    }
+
+    let (bot_name, creator, rooms) = (name.clone(), username.clone(), request.rooms.clone());
+    let result = db.run(move |conn| insert_bot(conn, &bot_name, &creator, &rooms)).await;
+
+    match result {
+        Ok(Some((bot_id, token_id, token))) => {
+            info!("User '{}' created bot '{}'", username, name);
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&IssuedToken {
+                    bot_id,
+                    name,
+                    token_id,
+                    token,
+                    rooms: request.rooms,
+                }),
+                StatusCode::CREATED,
+            )))
+        }
+        Ok(None) => Ok(json_message("Name already taken.", StatusCode::BAD_REQUEST)),
+        Err(e) => {
+            error!("Failed to create bot: {:?}", e);
+            Ok(json_message("Failed to create bot.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+pub async fn handle_create_token(
+    bot_id: i64,
+    request: CreateToken,
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    if !valid_scope(&request.rooms) {
+        return Ok(json_message("Token scope must list known rooms.", StatusCode::BAD_REQUEST));
+    }
+
+    let rooms = request.rooms.clone();
+    let result = db
+        .run(move |conn| {
+            conn.query_row(
+                "SELECT name FROM bots WHERE id = ?1 AND created_by = ?2",
+                params![bot_id, username],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+            .and_then(|name| match name {
+                Some(name) => Ok(Some((name, insert_token(conn, bot_id, &rooms)?))),
+                None => Ok(None),
+            })
+        })
+        .await;
+
+    match result {
+        Ok(Some((name, (token_id, token)))) => Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&IssuedToken {
+                bot_id,
+                name,
+                token_id,
+                token,
+                rooms: request.rooms,
+            }),
+            StatusCode::CREATED,
+        ))),
+        Ok(None) => Ok(json_message("Bot not found.", StatusCode::NOT_FOUND)),
+        Err(e) => {
+            error!("Failed to create token for bot {}: {:?}", bot_id, e);
+            Ok(json_message("Failed to create token.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+pub async fn handle_revoke_token(
+    bot_id: i64,
+    token_id: i64,
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "DELETE FROM api_tokens WHERE id = ?1 AND bot_id = ?2
+                     AND bot_id IN (SELECT id FROM bots WHERE created_by = ?3)",
+                params![token_id, bot_id, username],
+            )
+        })
+        .await;
+
+    match result {
+        Ok(0) => Ok(json_message("Token not found.", StatusCode::NOT_FOUND)),
+        Ok(_) => Ok(json_message("Token revoked.", StatusCode::OK)),
+        Err(e) => {
+            error!("Failed to revoke token {}: {:?}", token_id, e);
+            Ok(json_message("Failed to revoke token.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby() -> Vec<String> {
+        vec![chat::LOBBY_ROOM.to_string()]
+    }
+
+    #[tokio::test]
+    async fn users_and_bots_share_names() {
+        let (_dir, db) = db::open_temp().await;
+        db.run(|conn| {
+            conn.execute("INSERT INTO users (username, password) VALUES ('alice', 'x')", [])?;
+            assert!(insert_bot(conn, "alice", "alice", &lobby())?.is_none());
+            assert!(insert_bot(conn, "helper", "alice", &lobby())?.is_some());
+            assert!(insert_bot(conn, "helper", "alice", &lobby())?.is_none());
+            assert!(name_taken(conn, "helper")?);
+            assert!(!name_taken(conn, "bob")?);
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_users_and_bots_cannot_take_the_same_name() {
+        let (_dir, db) = db::open_temp().await;
+        let attempts: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    db.run(move |conn| {
+                        if i % 2 == 0 {
+                            insert_bot(conn, "helper", "alice", &lobby()).map(|bot| bot.is_some())
+                        } else {
+                            crate::auth::insert_user(conn, "helper", "hash")
+                        }
+                    })
+                    .await
+                })
+            })
+            .collect();
+        let mut created = 0;
+        for attempt in attempts {
+            created += attempt.await.unwrap().unwrap() as usize;
+        }
+        assert_eq!(created, 1);
+    }
+
+    #[tokio::test]
+    async fn authenticates_by_token_hash() {
+        let (_dir, db) = db::open_temp().await;
+        let (_, token_id, token) = db
+            .run(|conn| insert_bot(conn, "helper", "alice", &[ALL_ROOMS.to_string()]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(token.starts_with("bot_"));
+
+        let (stored, last_used): (String, Option<i64>) = db
+            .run(move |conn| {
+                conn.query_row(
+                    "SELECT token_hash, last_used_at FROM api_tokens WHERE id = ?1",
+                    params![token_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(stored, hash_token(&token));
+        assert_ne!(stored, token);
+        assert_eq!(last_used, None);
+
+        let bot = authenticate(&db, Some(&format!("Bearer {}", token))).await.unwrap();
+        assert_eq!(bot.name, "helper");
+        assert_eq!(bot.rooms, [ALL_ROOMS]);
+        let last_used: Option<i64> = db
+            .run(move |conn| {
+                conn.query_row("SELECT last_used_at FROM api_tokens WHERE id = ?1", params![token_id], |row| {
+                    row.get(0)
+                })
+            })
+            .await
+            .unwrap();
+        assert!(last_used.is_some());
+
+        assert!(authenticate(&db, Some(&format!("Bearer {}x", token))).await.is_none());
+        assert!(authenticate(&db, Some(&format!("Bearer {}", stored))).await.is_none());
+        assert!(authenticate(&db, Some(&token)).await.is_none());
+        assert!(authenticate(&db, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tokens_keep_their_own_scope() {
+        let (_dir, db) = db::open_temp().await;
+        let (bot_id, _, everywhere) = db
+            .run(|conn| insert_bot(conn, "helper", "alice", &[ALL_ROOMS.to_string()]))
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, lobby_only) = db.run(move |conn| insert_token(conn, bot_id, &lobby())).await.unwrap();
+
+        let bearer = |token: &str| format!("Bearer {}", token);
+        assert_eq!(authenticate(&db, Some(&bearer(&everywhere))).await.unwrap().rooms, [ALL_ROOMS]);
+        assert_eq!(authenticate(&db, Some(&bearer(&lobby_only))).await.unwrap().rooms, lobby());
+    }
+
+    #[test]
+    fn scopes_list_known_rooms() {
+        assert!(valid_scope(&[ALL_ROOMS.to_string()]));
+        assert!(valid_scope(&lobby()));
+        assert!(!valid_scope(&[]));
+        assert!(!valid_scope(&["lobby".to_string(), "elsewhere".to_string()]));
+    }
+}
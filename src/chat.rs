@@ -1,19 +1,55 @@
-
-use warp::Filter;
-use warp::ws::{Message, WebSocket};
 use std::sync::{Arc, Mutex};
-use futures::{FutureExt, StreamExt};
-use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use lazy_static::lazy_static;
+use serde::Deserialize;
 use uuid::Uuid;
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::db::Db;
+use crate::handling_files::{self, SaveError};
+use crate::offline::{self, QueuedKind};
+use crate::protocol::{self, ClientFrame, Encoding, ErrorCode, Outbound, ServerFrame};
 use crate::shared::SESSIONS;
+use crate::uploads;
+use crate::webhooks::{self, WebhookEvent};
+use crate::websocket::{self, Message, Receiver, Sender, Ws};
+
+/// The single room every connected client is part of.
+pub const LOBBY_ROOM: &str = "lobby";
+
+/// How long a request id is remembered. Clients resend unacknowledged
+/// frames when they reconnect, which is well within this.
+const RECEIPT_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// Set to the message id once an in-flight request is accepted.
+type Outcome = watch::Sender<Option<Option<i64>>>;
+
+lazy_static! {
+    /// Requests being handled right now, by sender and request id. A resend
+    /// of one of these waits for the outcome.
+    static ref IN_FLIGHT: Mutex<HashMap<(String, String), Outcome>> = Mutex::new(HashMap::new());
+}
+
+pub fn room_exists(room: &str) -> bool {
+    room == LOBBY_ROOM
+}
 
 pub type Clients = Arc<Mutex<HashMap<Uuid, Client>>>;
 
 pub struct Client {
-    pub sender: UnboundedSender<Message>,
+    pub username: String,
+    pub encoding: Encoding,
+    /// Encoded frames, framed by whichever transport the client uses.
+    pub sender: UnboundedSender<Outbound>,
+}
+
+/// Query parameters accepted on the WebSocket upgrade request.
+#[derive(Deserialize, Debug)]
+pub struct WsQuery {
+    /// Stable id of the browser or app, so queued messages reach every
+    /// device once. Connections without one share a single delivery record.
+    pub device: Option<String>,
 }
 
 #[derive(Debug)]
@@ -21,34 +57,20 @@ pub struct Unauthorized;
 
 impl warp::reject::Reject for Unauthorized {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum MessageType {
-    System,
-    User,
-    Init,
-    File,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatMessage {
-    pub message_type: MessageType,
-    pub content: String,
-    pub sender_username: Option<String>,
-    pub filename: Option<String>,
-    pub file_id: Option<String>,
-}
-
 pub async fn handle_ws_auth(
-    ws: warp::ws::Ws,
+    ws: Ws,
+    query: WsQuery,
     session_token: Option<String>,
     clients: Clients,
+    db: Db,
 ) -> Result<Box<dyn warp::Reply + Send>, warp::Rejection> {
     if let Some(token) = session_token {
         // Check if the session token is valid
         let sessions = SESSIONS.lock().unwrap();
         if sessions.contains_key(&token) {
+            let device = offline::device_id(query.device.as_deref());
             // handle the WebSocket connection
-            let reply = ws.on_upgrade(move |socket| handle_connection(socket, clients, token));
+            let reply = ws.on_upgrade(move |ws_tx, ws_rx| handle_connection(ws_tx, ws_rx, clients, db, token, device));
             Ok(Box::new(reply))
         } else {
             Err(warp::reject::custom(Unauthorized))
@@ -58,126 +80,420 @@ pub async fn handle_ws_auth(
     }
 }
 
-pub async fn handle_connection(ws: WebSocket, clients: Clients, session_token: String) {
-    let username = {
-        let sessions = SESSIONS.lock().unwrap();
-        sessions
-            .get(&session_token)
-            .cloned()
-            .unwrap_or("Unknown".to_string())
-    };
-
+/// Adds a client to the registry, greets it and announces it to everyone
+/// else. Every transport (WebSocket, SSE, long polling) registers here once
+/// the protocol version is settled and receives the same serialized frames
+/// through the returned channel, starting with anything queued for `device`
+/// while the user was offline.
+pub async fn register_client(
+    clients: &Clients,
+    db: &Db,
+    username: &str,
+    version: u32,
+    encoding: Encoding,
+    device: &str,
+) -> (Uuid, UnboundedReceiver<Outbound>) {
     // Assign a unique ID to the client
     let client_id = Uuid::new_v4();
     println!("Client {} connected as {}", client_id, username);
 
-    // Split the WebSocket into sender and receiver
-    let (ws_tx, mut ws_rx) = ws.split();
-
     // Create a channel to send messages to the client
     let (tx, rx) = mpsc::unbounded_channel();
-    let rx = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
-    let send_to_client = rx.map(Ok).forward(ws_tx).map(|result| {
-        if let Err(e) = result {
-            eprintln!("WebSocket send error: {}", e);
-        }
-    });
 
     // Insert the sender into the shared clients list
-    let client = Client { sender: tx.clone() };
+    let client = Client {
+        username: username.to_string(),
+        encoding,
+        sender: tx.clone(),
+    };
     clients.lock().unwrap().insert(client_id, client);
 
-    let init_message = ChatMessage {
-        message_type: MessageType::Init,
-        content: String::new(), // No content needed for init message
-        sender_username: Some(username.clone()),
-        filename: None,
-        file_id: None,
-    };
-    let _ = tx.send(Message::text(serde_json::to_string(&init_message).unwrap()));
-
-    let welcome_message = format!("Welcome to the chat, {}!", username);
-    let _ = tx.send(Message::text(
-        serde_json::to_string(&ChatMessage {
-            message_type: MessageType::System,
-            content: welcome_message,
-            sender_username: None,
-            filename: None,
-            file_id: None,
+    // The client only knows the encoding once it has read the welcome.
+    let welcome = ServerFrame::Welcome {
+        version,
+        username: username.to_string(),
+        encoding,
+    };
+    let _ = tx.send(Outbound::Text(welcome.to_json()));
+    let _ = tx.send(ServerFrame::system(format!("Welcome to the chat, {}!", username)).encode(encoding));
+
+    let queued = offline::flush(db, username, device).await;
+    if !queued.is_empty() {
+        let notice = format!("{} message(s) arrived while you were away.", queued.len());
+        let _ = tx.send(ServerFrame::system(notice).encode(encoding));
+        for frame in &queued {
+            let _ = tx.send(frame.encode(encoding));
+        }
+    }
+
+    broadcast_to_all(clients, &ServerFrame::system(format!("{} has joined the chat.", username))).await;
+    webhooks::dispatch(db, LOBBY_ROOM, WebhookEvent::Join, username, None).await;
+
+    (client_id, rx)
+}
+
+/// Removes a client from the registry and tells everyone it has left.
+pub async fn unregister_client(clients: &Clients, db: &Db, client_id: Uuid, username: &str) {
+    // Client disconnected, remove from the list
+    if clients.lock().unwrap().remove(&client_id).is_none() {
+        return;
+    }
+    println!("Client {} disconnected", client_id);
+
+    // Notify all clients that a user has left
+    broadcast_to_all(clients, &ServerFrame::system(format!("{} has left the chat.", username))).await;
+    webhooks::dispatch(db, LOBBY_ROOM, WebhookEvent::Leave, username, None).await;
+}
+
+/// Handles one frame sent by `username` after the handshake, whichever
+/// transport it came in on. Returns the frame to send back to the client:
+/// an ack if the frame carried a `request_id`, otherwise an error frame if
+/// it was rejected.
+pub async fn handle_frame(
+    frame: ClientFrame,
+    username: &str,
+    clients: &Clients,
+    db: &Db,
+) -> Option<ServerFrame> {
+    // Upload setup and chunks answer with their own frames rather than acks.
+    let frame = match frame {
+        ClientFrame::UploadInit {
+            request_id,
+            upload_id,
+            filename,
+            size,
+            sha256,
+            strip_metadata,
+        } => {
+            let file = uploads::NewUpload {
+                filename,
+                size,
+                sha256,
+                strip_metadata,
+            };
+            let result = uploads::handle_init(request_id.clone(), upload_id, file, username, db).await;
+            return Some(result.unwrap_or_else(|(code, reason)| match request_id {
+                Some(request_id) => ServerFrame::rejected(&request_id, code, reason),
+                None => ServerFrame::error(None, code, reason),
+            }));
+        }
+        ClientFrame::UploadChunk { upload_id, index, data } => {
+            let result = uploads::handle_chunk(upload_id, index, data, username).await;
+            return Some(result.unwrap_or_else(|(code, message)| ServerFrame::error(None, code, message)));
+        }
+        frame => frame,
+    };
+
+    let request_id = frame.request_id().map(String::from);
+    let mut in_flight = None;
+    if let Some(request_id) = &request_id {
+        match claim_request(db, username, request_id).await {
+            Claim::New(claim) => in_flight = Some(claim),
+            Claim::Done(message_id) => return Some(ServerFrame::accepted(request_id, message_id, true)),
+        }
+    }
+
+    let result = match frame {
+        ClientFrame::Hello { .. } => Err((
+            ErrorCode::UnexpectedFrame,
+            "The handshake has already been completed.".to_string(),
+        )),
+        ClientFrame::Message { content, .. } => {
+            if content.trim().is_empty() {
+                Err((ErrorCode::InvalidRequest, "Message cannot be empty.".to_string()))
+            } else {
+                Ok(publish_message(clients, db, username, content).await)
+            }
+        }
+        ClientFrame::File {
+            filename,
+            content,
+            strip_metadata,
+            ..
+        } => handle_file_message(filename, content, strip_metadata, username, clients, db).await,
+        ClientFrame::UploadFinish { upload_id, .. } => uploads::handle_finish(upload_id, username, clients, db).await,
+        ClientFrame::UploadInit { .. } | ClientFrame::UploadChunk { .. } => unreachable!("handled above"),
+        ClientFrame::Direct { recipient, content, .. } => {
+            if content.trim().is_empty() {
+                Err((ErrorCode::InvalidRequest, "Message cannot be empty.".to_string()))
+            } else if !offline::user_exists(db, &recipient).await {
+                Err((ErrorCode::InvalidRequest, format!("Unknown user {}.", recipient)))
+            } else {
+                Ok(publish_direct(clients, db, username, recipient, content).await)
+            }
+        }
+    };
+
+    match (request_id, result) {
+        (Some(request_id), Ok(message_id)) => {
+            record_request(db, username, &request_id, message_id).await;
+            if let Some(claim) = in_flight {
+                claim.accept(message_id);
+            }
+            Some(ServerFrame::accepted(&request_id, message_id, false))
+        }
+        (Some(request_id), Err((code, reason))) => {
+            // Rejected requests may be retried with the same id. Resends
+            // waiting on this one are handled anew once the claim drops.
+            release_request(db, username, &request_id).await;
+            drop(in_flight);
+            Some(ServerFrame::rejected(&request_id, code, reason))
+        }
+        (None, Ok(_)) => None,
+        (None, Err((code, message))) => Some(ServerFrame::error(None, code, message)),
+    }
+}
+
+enum Claim {
+    /// The request is new and should be handled.
+    New(InFlight),
+    /// The request was accepted before, under this message id (`None` if
+    /// the message could not be stored).
+    Done(Option<i64>),
+}
+
+/// A request being handled. Dropping it without `accept` lets resends that
+/// are waiting on it claim the id themselves.
+struct InFlight {
+    key: (String, String),
+}
+
+impl InFlight {
+    fn accept(self, message_id: Option<i64>) {
+        if let Some(outcome) = IN_FLIGHT.lock().unwrap().get(&self.key) {
+            outcome.send_replace(Some(message_id));
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Reserves `request_id` for `sender`. If the same id is being handled
+/// already, waits for that to finish: an accepted request is `Done`, a
+/// rejected one may be retried and is claimed again.
+async fn claim_request(db: &Db, sender: &str, request_id: &str) -> Claim {
+    let key = (sender.to_string(), request_id.to_string());
+    let claim = loop {
+        let waiting = {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(outcome) => outcome.subscribe(),
+                None => {
+                    in_flight.insert(key.clone(), watch::channel(None).0);
+                    break InFlight { key };
+                }
+            }
+        };
+        // Fails if the first copy was rejected, and so never accepted.
+        if let Ok(outcome) = watch_outcome(waiting).await {
+            return Claim::Done(outcome);
+        }
+    };
+
+    let (name, id) = claim.key.clone();
+    let claimed = db
+        .run(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO message_receipts (sender, request_id, created_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![name, id, crate::db::now()],
+            )?;
+            if inserted > 0 {
+                return Ok(None);
+            }
+            conn.query_row(
+                "SELECT message_id FROM message_receipts WHERE sender = ?1 AND request_id = ?2",
+                rusqlite::params![name, id],
+                |row| row.get(0),
+            )
+            .map(Some)
         })
-            .unwrap(),
-    ));
-
-    let join_message = format!("{} has joined the chat.", username);
-    let join_chat_msg = ChatMessage {
-        message_type: MessageType::System,
-        content: join_message,
-        sender_username: None,
-        filename: None,
-        file_id: None,
-    };
-    let serialized = serde_json::to_string(&join_chat_msg).unwrap();
-    broadcast_to_all(&clients, serialized).await;
-
-    let clients_clone = clients.clone();
-    let username_clone = username.clone();
-    let receive_from_client = async move {
-        while let Some(result) = ws_rx.next().await {
-            match result {
-                Ok(msg) => {
-                    if msg.is_text() {
-                        let msg_text = msg.to_str().unwrap_or("").to_string();
-
-                        // Try to parse the message as a ChatMessage
-                        if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&msg_text) {
-                            match chat_msg.message_type {
-                                MessageType::User => {
-                                    // Broadcast text message
-                                    let serialized = serde_json::to_string(&chat_msg).unwrap();
-                                    broadcast_to_all(&clients_clone, serialized).await;
-                                }
-                                MessageType::File => {
-                                    // Handle file message
-                                    handle_file_message(chat_msg, &clients_clone).await;
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            // If parsing fails, treat it as a regular text message
-                            let chat_msg = ChatMessage {
-                                message_type: MessageType::User,
-                                content: msg_text,
-                                sender_username: Some(username_clone.clone()),
-                                filename: None,
-                                file_id: None,
-                            };
-                            let serialized = serde_json::to_string(&chat_msg).unwrap();
-                            broadcast_to_all(&clients_clone, serialized).await;
-                        }
-                    }
+        .await;
+    match claimed {
+        Ok(Some(message_id)) => Claim::Done(message_id),
+        Ok(None) => Claim::New(claim),
+        Err(e) => {
+            // Without the receipt a resend may be posted twice, which is
+            // better than dropping the message.
+            eprintln!("Failed to record request {} from {}: {}", request_id, sender, e);
+            Claim::New(claim)
+        }
+    }
+}
+
+async fn watch_outcome(mut outcome: watch::Receiver<Option<Option<i64>>>) -> Result<Option<i64>, watch::error::RecvError> {
+    let message_id = outcome.wait_for(Option::is_some).await?;
+    Ok(message_id.expect("waited for"))
+}
+
+async fn record_request(db: &Db, sender: &str, request_id: &str, message_id: Option<i64>) {
+    let (name, id) = (sender.to_string(), request_id.to_string());
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "UPDATE message_receipts SET message_id = ?3 WHERE sender = ?1 AND request_id = ?2",
+                rusqlite::params![name, id, message_id],
+            )
+        })
+        .await;
+    if let Err(e) = result {
+        eprintln!("Failed to record request {} from {}: {}", request_id, sender, e);
+    }
+}
+
+async fn release_request(db: &Db, sender: &str, request_id: &str) {
+    let (name, id) = (sender.to_string(), request_id.to_string());
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "DELETE FROM message_receipts WHERE sender = ?1 AND request_id = ?2",
+                rusqlite::params![name, id],
+            )
+        })
+        .await;
+    if let Err(e) = result {
+        eprintln!("Failed to release request {} from {}: {}", request_id, sender, e);
+    }
+}
+
+/// Forgets request ids older than `RECEIPT_TTL_SECS`; a resend that late is
+/// posted again.
+pub async fn prune_receipts(db: &Db) {
+    let cutoff = crate::db::now() - RECEIPT_TTL_SECS;
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "DELETE FROM message_receipts WHERE created_at < ?1",
+                rusqlite::params![cutoff],
+            )
+        })
+        .await;
+    if let Err(e) = result {
+        eprintln!("Failed to prune message receipts: {}", e);
+    }
+}
+
+/// Parses and handles one JSON text frame, returning the ack or error frame
+/// to send back, if any.
+pub async fn handle_text(msg_text: &str, username: &str, clients: &Clients, db: &Db) -> Option<ServerFrame> {
+    match ClientFrame::parse(msg_text) {
+        Ok(frame) => handle_frame(frame, username, clients, db).await,
+        Err(error) => Some(error),
+    }
+}
+
+/// Like `handle_text`, for binary frames in the negotiated encoding.
+pub async fn handle_binary(
+    data: &[u8],
+    encoding: Encoding,
+    username: &str,
+    clients: &Clients,
+    db: &Db,
+) -> Option<ServerFrame> {
+    match ClientFrame::decode(data, encoding) {
+        Ok(frame) => handle_frame(frame, username, clients, db).await,
+        Err(error) => Some(error),
+    }
+}
+
+/// Waits for the client's `Hello` and settles on a protocol version and
+/// encoding. Returns `None` if the socket closed or no version is shared.
+async fn websocket_handshake(ws_tx: &mut Sender, ws_rx: &mut Receiver) -> Option<(u32, Encoding)> {
+    loop {
+        let msg = match websocket::receive(ws_rx).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return None,
+            Err(e) => {
+                eprintln!("WebSocket error during handshake: {}", e);
+                return None;
+            }
+        };
+
+        let parsed = match msg {
+            Message::Text(text) => ClientFrame::parse(&text),
+            Message::Binary(_) => Err(ServerFrame::error(
+                None,
+                ErrorCode::HandshakeRequired,
+                "Send a hello frame as JSON text first.",
+            )),
+        };
+
+        let reply = match parsed {
+            Ok(ClientFrame::Hello { versions, encodings }) => match protocol::negotiate(&versions) {
+                Some(version) => return Some((version, Encoding::negotiate(&encodings))),
+                None => {
+                    let _ = websocket::send(ws_tx, Outbound::Text(protocol::unsupported_version().to_json())).await;
+                    let _ = ws_tx.close().await;
+                    return None;
                 }
+            },
+            Ok(frame) => ServerFrame::error(
+                frame.request_id(),
+                ErrorCode::HandshakeRequired,
+                "Send a hello frame first.",
+            ),
+            Err(error) => error,
+        };
+        if websocket::send(ws_tx, Outbound::Text(reply.to_json())).await.is_err() {
+            return None;
+        }
+    }
+}
+
+pub async fn handle_connection(
+    mut ws_tx: Sender,
+    mut ws_rx: Receiver,
+    clients: Clients,
+    db: Db,
+    session_token: String,
+    device: String,
+) {
+    let username = {
+        let sessions = SESSIONS.lock().unwrap();
+        sessions
+            .get(&session_token)
+            .cloned()
+            .unwrap_or("Unknown".to_string())
+    };
+
+    let (version, encoding) = match websocket_handshake(&mut ws_tx, &mut ws_rx).await {
+        Some(negotiated) => negotiated,
+        None => return,
+    };
+
+    let (client_id, mut rx) = register_client(&clients, &db, &username, version, encoding, &device).await;
+    let send_to_client = async move {
+        while let Some(outbound) = rx.recv().await {
+            if let Err(e) = websocket::send(&mut ws_tx, outbound).await {
+                eprintln!("WebSocket send error: {}", e);
+                break;
+            }
+        }
+        let _ = ws_tx.close().await;
+    };
+
+    let receive_from_client = async move {
+        loop {
+            // JSON text is always accepted, binary frames must use the
+            // negotiated encoding.
+            let reply = match websocket::receive(&mut ws_rx).await {
+                Ok(Some(Message::Text(text))) => handle_text(&text, &username, &clients, &db).await,
+                Ok(Some(Message::Binary(data))) => handle_binary(&data, encoding, &username, &clients, &db).await,
+                Ok(None) => break,
                 Err(e) => {
                     eprintln!("WebSocket error (client {}): {}", client_id, e);
                     break;
                 }
+            };
+            if let Some(reply) = reply {
+                send_to(&clients, client_id, &reply);
             }
         }
 
-        // Client disconnected, remove from the list
-        clients_clone.lock().unwrap().remove(&client_id);
-        println!("Client {} disconnected", client_id);
-
-        // Notify all clients that a user has left
-        let leave_message = format!("{} has left the chat.", username_clone);
-        let leave_chat_msg = ChatMessage {
-            message_type: MessageType::System,
-            content: leave_message,
-            sender_username: None,
-            filename: None,
-            file_id: None,
-        };
-        let serialized = serde_json::to_string(&leave_chat_msg).unwrap();
-        broadcast_to_all(&clients_clone, serialized).await;
+        unregister_client(&clients, &db, client_id, &username).await;
     };
 
     // Run both sending and receiving concurrently
@@ -185,59 +501,330 @@ pub async fn handle_connection(ws: WebSocket, clients: Clients, session_token: S
     tokio::spawn(receive_from_client);
 }
 
-pub async fn broadcast_to_all(clients: &Clients, message: String) {
+pub fn send_to(clients: &Clients, client_id: Uuid, frame: &ServerFrame) {
+    if let Some(client) = clients.lock().unwrap().get(&client_id) {
+        let _ = client.sender.send(frame.encode(client.encoding));
+    }
+}
+
+/// Sends a frame to every connection of `username`. Returns false if the
+/// user has none.
+pub fn send_to_user(clients: &Clients, username: &str, frame: &ServerFrame) -> bool {
+    let mut encoded: HashMap<Encoding, Outbound> = HashMap::new();
+    let mut delivered = false;
+    let clients_lock = clients.lock().unwrap();
+    for client in clients_lock.values().filter(|client| client.username == username) {
+        let message = encoded
+            .entry(client.encoding)
+            .or_insert_with(|| frame.encode(client.encoding));
+        delivered |= client.sender.send(message.clone()).is_ok();
+    }
+    delivered
+}
+
+fn is_online(clients: &Clients, username: &str) -> bool {
+    clients.lock().unwrap().values().any(|client| client.username == username)
+}
+
+pub async fn broadcast_to_all(clients: &Clients, frame: &ServerFrame) {
+    // Encode once per encoding in use rather than once per client.
+    let mut encoded: HashMap<Encoding, Outbound> = HashMap::new();
     let clients_lock = clients.lock().unwrap();
     for (_, client) in clients_lock.iter() {
-        let _ = client.sender.send(Message::text(message.clone()));
+        let message = encoded
+            .entry(client.encoding)
+            .or_insert_with(|| frame.encode(client.encoding));
+        let _ = client.sender.send(message.clone());
+    }
+}
+
+async fn store_message(
+    db: &Db,
+    kind: &'static str,
+    sender: &str,
+    content: &str,
+    filename: Option<&str>,
+    file_id: Option<&str>,
+    sent_at: i64,
+) -> Option<i64> {
+    let (name, content) = (sender.to_string(), content.to_string());
+    let (filename, file_id) = (filename.map(String::from), file_id.map(String::from));
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "INSERT INTO messages (room, kind, sender, content, filename, file_id, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                rusqlite::params![LOBBY_ROOM, kind, name, content, filename, file_id, sent_at],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await;
+    match result {
+        Ok(id) => Some(id),
+        Err(e) => {
+            // History is best effort, the live chat keeps working without it.
+            eprintln!("Failed to store message from {}: {}", sender, e);
+            None
+        }
     }
 }
 
-pub async fn handle_file_message(chat_msg: ChatMessage, clients: &Clients) {
-    use tokio::fs;
-    use uuid::Uuid;
-    use base64;
+/// Stores and broadcasts a user message from `sender` to the lobby. Every
+/// way of posting a message (WebSocket, HTTP API) goes through here.
+pub async fn publish_message(clients: &Clients, db: &Db, sender: &str, content: String) -> Option<i64> {
+    let sent_at = crate::db::now();
+    let id = store_message(db, "message", sender, &content, None, None, sent_at).await;
+    let mentioned = offline::mentions(&content);
+    let frame = ServerFrame::Message {
+        id,
+        sender: sender.to_string(),
+        content,
+        sent_at,
+    };
+    broadcast_to_all(clients, &frame).await;
+    webhooks::dispatch(db, LOBBY_ROOM, WebhookEvent::Message, sender, Some(&frame)).await;
 
-    // Ensure the necessary fields are present
-    if let (Some(content), Some(filename), Some(sender_username)) = (
-        Some(chat_msg.content),
-        chat_msg.filename.clone(),
-        chat_msg.sender_username.clone(),
-    ) {
-        // Decode the Base64 file content
-        let file_data = match base64::decode(&content) {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!("Failed to decode file content: {}", e);
-                return;
+    // Online users just saw it; keep it for mentioned users who did not.
+    if let Some(id) = id {
+        for name in mentioned {
+            if name != sender && !is_online(clients, &name) && offline::user_exists(db, &name).await {
+                offline::enqueue(db, &name, QueuedKind::Mention, id).await;
             }
-        };
+        }
+    }
+    id
+}
+
+/// Stores a direct message and sends it to both sides' connections,
+/// queueing it for the recipient if they have none.
+pub async fn publish_direct(
+    clients: &Clients,
+    db: &Db,
+    sender: &str,
+    recipient: String,
+    content: String,
+) -> Option<i64> {
+    let sent_at = crate::db::now();
+    let (name, to, text) = (sender.to_string(), recipient.clone(), content.clone());
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "INSERT INTO direct_messages (sender, recipient, content, created_at) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![name, to, text, sent_at],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await;
+    let id = match result {
+        Ok(id) => Some(id),
+        Err(e) => {
+            eprintln!("Failed to store direct message from {}: {}", sender, e);
+            None
+        }
+    };
+
+    let frame = ServerFrame::Direct {
+        id,
+        sender: sender.to_string(),
+        recipient: recipient.clone(),
+        content,
+        sent_at,
+    };
+    if !send_to_user(clients, &recipient, &frame) {
+        if let Some(id) = id {
+            offline::enqueue(db, &recipient, QueuedKind::Direct, id).await;
+        }
+    }
+    if recipient != sender {
+        send_to_user(clients, sender, &frame);
+    }
+    id
+}
 
-        // Generate a unique file ID
-        let file_id = Uuid::new_v4().to_string();
+/// Stores and announces a file that `sender` has already saved under `file_id`.
+/// `filename` is the name the client gave and is sanitized here.
+pub async fn publish_file(
+    clients: &Clients,
+    db: &Db,
+    sender: &str,
+    filename: String,
+    file_id: String,
+) -> Option<i64> {
+    let filename = handling_files::sanitize_filename(&filename);
+    let sent_at = crate::db::now();
+    let id = store_message(db, "file", sender, "", Some(&filename), Some(&file_id), sent_at).await;
+    let image = handling_files::find_image(db, &file_id).await.map(Box::new);
+    let frame = ServerFrame::File {
+        id,
+        sender: sender.to_string(),
+        file_id,
+        filename,
+        sent_at,
+        image,
+    };
+    broadcast_to_all(clients, &frame).await;
+    webhooks::dispatch(db, LOBBY_ROOM, WebhookEvent::File, sender, Some(&frame)).await;
+    id
+}
 
-        // Save the file to the uploads directory
-        let filepath = format!("./uploads/{}_{}", file_id, filename);
+/// Deletes the file `file_id` and tells the room its message now has no
+/// file. Returns false if there was no such file.
+pub async fn publish_file_deletion(
+    clients: &Clients,
+    db: &Db,
+    file_id: &str,
+    deleted_by: Option<&str>,
+) -> rusqlite::Result<bool> {
+    let deleted = match handling_files::delete_file(db, file_id, deleted_by).await? {
+        Some(deleted) => deleted,
+        None => return Ok(false),
+    };
+    println!(
+        "Deleted file {} from {} ({})",
+        file_id,
+        deleted.room,
+        deleted_by.unwrap_or("retention policy")
+    );
+    let frame = ServerFrame::FileDeleted {
+        id: deleted.message_id,
+        file_id: file_id.to_string(),
+        deleted_by: deleted_by.map(String::from),
+        deleted_at: deleted.deleted_at,
+    };
+    broadcast_to_all(clients, &frame).await;
+    Ok(true)
+}
 
-        if let Err(e) = fs::create_dir_all("./uploads").await {
-            eprintln!("Failed to create uploads directory: {}", e);
-            return;
+/// Tells `uploader` on all their connections that `filename` was
+/// quarantined, if that is why saving it failed, since nothing appears in
+/// the room.
+pub fn notify_quarantined(clients: &Clients, uploader: &str, filename: &str, error: &SaveError) {
+    if let SaveError::Infected(signature) = error {
+        let notice = ServerFrame::system(format!(
+            "Your file {} was not shared: the malware scanner flagged it as {}.",
+            filename, signature
+        ));
+        send_to_user(clients, uploader, &notice);
+    }
+}
+
+pub async fn handle_file_message(
+    filename: String,
+    file_data: Vec<u8>,
+    strip_metadata: Option<bool>,
+    sender: &str,
+    clients: &Clients,
+    db: &Db,
+) -> Result<Option<i64>, (ErrorCode, String)> {
+    if filename.trim().is_empty() {
+        return Err((ErrorCode::InvalidRequest, "Filename cannot be empty.".into()));
+    }
+
+    let file_id = match handling_files::save_upload(db, sender, &filename, &file_data, strip_metadata).await {
+        Ok(file_id) => file_id,
+        Err(e) => {
+            eprintln!("Failed to save file {}: {}", filename, e);
+            notify_quarantined(clients, sender, &filename, &e);
+            return Err(e.to_frame_error());
         }
+    };
+
+    // Broadcast the file message to all clients
+    Ok(publish_file(clients, db, sender, filename, file_id).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prunes_old_receipts_only() {
+        let (_dir, db) = crate::db::open_temp().await;
+        assert!(matches!(claim_request(&db, "alice", "new").await, Claim::New(_)));
+        db.run(|conn| {
+            conn.execute(
+                "INSERT INTO message_receipts (sender, request_id, message_id, created_at) VALUES ('alice', 'old', 1, ?1)",
+                rusqlite::params![crate::db::now() - RECEIPT_TTL_SECS - 1],
+            )
+        })
+        .await
+        .unwrap();
+
+        prune_receipts(&db).await;
+
+        let left: Vec<String> = db
+            .run(|conn| {
+                conn.prepare("SELECT request_id FROM message_receipts")?
+                    .query_map([], |row| row.get(0))?
+                    .collect()
+            })
+            .await
+            .unwrap();
+        assert_eq!(left, vec!["new".to_string()]);
+        // The pruned id counts as new again.
+        assert!(matches!(claim_request(&db, "alice", "old").await, Claim::New(_)));
+    }
 
-        if let Err(e) = fs::write(&filepath, &file_data).await {
-            eprintln!("Failed to save file {}: {}", filepath, e);
-            return;
+    async fn done(claim: Claim) -> Option<i64> {
+        match claim {
+            Claim::Done(message_id) => message_id,
+            Claim::New(_) => panic!("expected the request to be known"),
         }
+    }
 
-        // Broadcast the file message to all clients
-        let file_message = ChatMessage {
-            message_type: MessageType::File,
-            content: String::new(), // No content needed
-            sender_username: Some(sender_username),
-            filename: Some(filename),
-            file_id: Some(file_id),
+    // In-flight requests are tracked process-wide, so every test uses its
+    // own request ids.
+    #[tokio::test]
+    async fn resends_get_the_first_outcome() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let Claim::New(first) = claim_request(&db, "alice", "resent").await else {
+            panic!("expected a new request");
         };
 
-        let serialized = serde_json::to_string(&file_message).unwrap();
-        broadcast_to_all(clients, serialized).await;
+        // A resend while the first copy is in flight waits for it.
+        let resend = tokio::spawn({
+            let db = db.clone();
+            async move { done(claim_request(&db, "alice", "resent").await).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!resend.is_finished());
+        // Other senders' ids are separate.
+        assert!(matches!(claim_request(&db, "bob", "resent").await, Claim::New(_)));
+
+        record_request(&db, "alice", "resent", Some(7)).await;
+        first.accept(Some(7));
+        assert_eq!(resend.await.unwrap(), Some(7));
+
+        // Later resends read the receipt.
+        assert_eq!(done(claim_request(&db, "alice", "resent").await).await, Some(7));
+    }
+
+    #[tokio::test]
+    async fn resends_of_rejected_requests_are_handled_again() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let Claim::New(first) = claim_request(&db, "alice", "rejected").await else {
+            panic!("expected a new request");
+        };
+
+        let resend = tokio::spawn({
+            let db = db.clone();
+            async move { matches!(claim_request(&db, "alice", "rejected").await, Claim::New(_)) }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!resend.is_finished());
+
+        release_request(&db, "alice", "rejected").await;
+        drop(first);
+        assert!(resend.await.unwrap(), "the resend should be handled as new");
+    }
+
+    #[tokio::test]
+    async fn unstored_messages_are_acked_as_done() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let Claim::New(first) = claim_request(&db, "alice", "unstored").await else {
+            panic!("expected a new request");
+        };
+        first.accept(None);
+        assert_eq!(done(claim_request(&db, "alice", "unstored").await).await, None);
     }
 }
@@ -0,0 +1,364 @@
+use std::mem;
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use soketto::base::{Header, OpCode};
+use soketto::extension::{Extension, Param};
+use soketto::{BoxedError, Storage};
+
+// permessage-deflate (RFC 7692), negotiated through Sec-WebSocket-Extensions
+// on the /ws upgrade, so browsers and other standard WebSocket clients use
+// it without knowing anything about this server. Messages of at least the
+// configured threshold are compressed and flagged with RSV1; smaller ones
+// go out as they are. Both directions keep their DEFLATE window from one
+// message to the next unless the client offers server_no_context_takeover.
+
+pub const NAME: &str = "permessage-deflate";
+
+/// The empty stored block a sync flush ends with, left off on the wire.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Same as the socket's maximum message size, so a small compressed message
+/// cannot inflate into something the socket would not have accepted.
+const MAX_INFLATED_SIZE: usize = 64 << 20;
+
+#[derive(Debug)]
+pub struct PerMessageDeflate {
+    /// Messages shorter than this are sent uncompressed.
+    threshold: usize,
+    /// Largest message accepted once inflated.
+    max_size: usize,
+    /// Start every outgoing message with an empty window, at the client's
+    /// request.
+    no_context_takeover: bool,
+    deflate: Compress,
+    inflate: Decompress,
+    /// Whether the first fragment of the message being received had RSV1 set.
+    fragmented: bool,
+}
+
+/// Picks the first offer in a `Sec-WebSocket-Extensions` request header that
+/// this server can accept. Returns the extension and the value to answer
+/// with, or `None` to go on without compression.
+pub fn negotiate(offers: &str, threshold: usize, level: u32) -> Option<(PerMessageDeflate, String)> {
+    offers.split(',').find_map(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        if !params.next()?.eq_ignore_ascii_case(NAME) {
+            return None;
+        }
+
+        let mut response = NAME.to_string();
+        let mut seen = Vec::new();
+        let mut no_context_takeover = false;
+        for param in params.filter(|param| !param.is_empty()) {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // An offer naming a parameter twice must be declined.
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            match (name, value) {
+                ("server_no_context_takeover", None) => {
+                    no_context_takeover = true;
+                    response.push_str("; server_no_context_takeover");
+                }
+                // flate2's default backend always compresses with a 32KB
+                // window, and inflating handles any window the client uses.
+                ("server_max_window_bits", Some("15")) => response.push_str("; server_max_window_bits=15"),
+                ("client_max_window_bits", None) | ("client_no_context_takeover", None) => {}
+                ("client_max_window_bits", Some(bits)) if is_window_bits(bits) => {}
+                _ => return None,
+            }
+        }
+        Some((PerMessageDeflate::new(threshold, level, no_context_takeover), response))
+    })
+}
+
+fn is_window_bits(value: &str) -> bool {
+    value.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits))
+}
+
+impl PerMessageDeflate {
+    pub fn new(threshold: usize, level: u32, no_context_takeover: bool) -> PerMessageDeflate {
+        PerMessageDeflate {
+            threshold,
+            max_size: MAX_INFLATED_SIZE,
+            no_context_takeover,
+            deflate: Compress::new(Compression::new(level), false),
+            inflate: Decompress::new(false),
+            fragmented: false,
+        }
+    }
+
+    /// Compresses one message, without the trailer.
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, BoxedError> {
+        // Room for DEFLATE's worst case, a few bytes per stored block.
+        let mut output = Vec::with_capacity(data.len() + data.len() / 1024 + 64);
+        let start = self.deflate.total_in();
+        loop {
+            let consumed = (self.deflate.total_in() - start) as usize;
+            self.deflate.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)?;
+            let consumed = (self.deflate.total_in() - start) as usize;
+            // With room to spare, the flush is complete.
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity());
+        }
+        if self.no_context_takeover {
+            self.deflate.reset();
+        }
+
+        if !output.ends_with(&TRAILER) {
+            return Err("Compressed message does not end with a sync flush.".into());
+        }
+        output.truncate(output.len() - TRAILER.len());
+        Ok(output)
+    }
+
+    /// Inflates one message received without the trailer.
+    fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, BoxedError> {
+        let mut input = Vec::with_capacity(data.len() + TRAILER.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&TRAILER);
+
+        let mut output = Vec::with_capacity((data.len() * 4).clamp(64, self.max_size));
+        let start = self.inflate.total_in();
+        loop {
+            let (total_in, total_out) = (self.inflate.total_in(), self.inflate.total_out());
+            let consumed = (total_in - start) as usize;
+            let status = self.inflate.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)?;
+            if output.len() > self.max_size {
+                return Err("Compressed message inflates past the size limit.".into());
+            }
+            // A block marked final ends the client's window.
+            if status == Status::StreamEnd {
+                self.inflate.reset(false);
+                break;
+            }
+            let consumed = (self.inflate.total_in() - start) as usize;
+            if consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().min(self.max_size + 1 - output.len()));
+            } else if (self.inflate.total_in(), self.inflate.total_out()) == (total_in, total_out) {
+                return Err("Truncated compressed message.".into());
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    // Parameters are settled by `negotiate` on the upgrade request, which
+    // soketto's own handshake never sees.
+    fn params(&self) -> &[Param<'_>] {
+        &[]
+    }
+
+    fn configure(&mut self, _params: &[Param]) -> Result<(), BoxedError> {
+        Ok(())
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        if !matches!(header.opcode(), OpCode::Text | OpCode::Binary) || data.as_ref().len() < self.threshold {
+            return Ok(());
+        }
+        *data = Storage::Owned(self.compress(data.as_ref())?);
+        header.set_rsv1(true);
+        Ok(())
+    }
+
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        // soketto hands over the first fragment of a message on its own, and
+        // the whole message once the last one has arrived, with that
+        // continuation frame's header, which never has RSV1 set.
+        if !header.is_fin() {
+            self.fragmented = header.is_rsv1();
+            return Ok(());
+        }
+        if !(header.is_rsv1() || mem::take(&mut self.fragmented)) {
+            return Ok(());
+        }
+        *data = self.decompress(data)?;
+        header.set_rsv1(false);
+        Ok(())
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        (true, false, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+
+    use super::*;
+
+    fn offer(offers: &str) -> Option<String> {
+        negotiate(offers, 0, 6).map(|(_, response)| response)
+    }
+
+    /// A message as a browser would send it: raw DEFLATE, sync flushed,
+    /// trailer removed.
+    fn client_compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.flush().unwrap();
+        let mut compressed = encoder.get_ref().clone();
+        assert!(compressed.ends_with(&TRAILER));
+        compressed.truncate(compressed.len() - TRAILER.len());
+        compressed
+    }
+
+    fn inflate(compressed: &[u8]) -> Vec<u8> {
+        let mut input = compressed.to_vec();
+        input.extend_from_slice(&TRAILER);
+        let mut inflated = Vec::new();
+        // The stream has no final block, so reading ends in an error once
+        // the data runs out; everything before it has been inflated.
+        let _ = DeflateDecoder::new(&input[..]).read_to_end(&mut inflated);
+        inflated
+    }
+
+    fn encode(extension: &mut PerMessageDeflate, data: &[u8]) -> (bool, Vec<u8>) {
+        let mut header = Header::new(OpCode::Text);
+        let mut storage = Storage::Shared(data);
+        extension.encode(&mut header, &mut storage).unwrap();
+        (header.is_rsv1(), storage.as_ref().to_vec())
+    }
+
+    #[test]
+    fn accepts_browser_offers() {
+        assert_eq!(offer("permessage-deflate; client_max_window_bits").as_deref(), Some(NAME));
+        assert_eq!(offer("permessage-deflate").as_deref(), Some(NAME));
+        assert_eq!(
+            offer("permessage-deflate; server_no_context_takeover; client_no_context_takeover").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=\"15\"; client_max_window_bits=10").as_deref(),
+            Some("permessage-deflate; server_max_window_bits=15")
+        );
+    }
+
+    #[test]
+    fn declines_what_it_cannot_honour() {
+        assert_eq!(offer(""), None);
+        assert_eq!(offer("x-webkit-deflate-frame"), None);
+        assert_eq!(offer("permessage-deflate; server_max_window_bits=10"), None);
+        assert_eq!(offer("permessage-deflate; client_max_window_bits=16"), None);
+        assert_eq!(offer("permessage-deflate; server_no_context_takeover; server_no_context_takeover"), None);
+        assert_eq!(offer("permessage-deflate; mystery"), None);
+        // Falls back to the next offer.
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=10, permessage-deflate").as_deref(),
+            Some(NAME)
+        );
+    }
+
+    #[test]
+    fn compresses_large_messages_only() {
+        let (mut extension, _) = negotiate(NAME, 64, 6).unwrap();
+        let (compressed, data) = encode(&mut extension, b"short");
+        assert!(!compressed);
+        assert_eq!(data, b"short");
+
+        let message = "{\"type\":\"message\",\"sender\":\"alice\",\"content\":\"hello\"}".repeat(20);
+        let (compressed, data) = encode(&mut extension, message.as_bytes());
+        assert!(compressed);
+        assert!(data.len() < message.len() / 4, "{} bytes", data.len());
+        assert_eq!(inflate(&data), message.as_bytes());
+    }
+
+    #[test]
+    fn keeps_the_window_unless_asked_not_to() {
+        let message = "The deploy went out at noon and the error rate is back to normal. ".repeat(4);
+
+        let (mut extension, _) = negotiate(NAME, 0, 6).unwrap();
+        let (_, first) = encode(&mut extension, message.as_bytes());
+        let (_, second) = encode(&mut extension, message.as_bytes());
+        assert!(second.len() < first.len());
+        let mut both = first.clone();
+        both.extend_from_slice(&TRAILER);
+        both.extend_from_slice(&second);
+        assert_eq!(inflate(&both), message.repeat(2).as_bytes());
+
+        let (mut extension, _) = negotiate("permessage-deflate; server_no_context_takeover", 0, 6).unwrap();
+        let (_, first) = encode(&mut extension, message.as_bytes());
+        let (_, second) = encode(&mut extension, message.as_bytes());
+        assert_eq!(first, second);
+        assert_eq!(inflate(&second), message.as_bytes());
+    }
+
+    #[test]
+    fn inflates_client_messages() {
+        let (mut extension, _) = negotiate(NAME, 0, 6).unwrap();
+        let message = b"{\"type\":\"message\",\"content\":\"hi\"}".repeat(10);
+
+        let mut header = Header::new(OpCode::Text);
+        header.set_rsv1(true);
+        let mut data = client_compress(&message);
+        extension.decode(&mut header, &mut data).unwrap();
+        assert_eq!(data, message);
+        assert!(!header.is_rsv1());
+
+        // Uncompressed messages are left alone.
+        let mut header = Header::new(OpCode::Text);
+        let mut data = b"plain".to_vec();
+        extension.decode(&mut header, &mut data).unwrap();
+        assert_eq!(data, b"plain");
+    }
+
+    #[test]
+    fn inflates_fragmented_messages_once_complete() {
+        let (mut extension, _) = negotiate(NAME, 0, 6).unwrap();
+        let message = b"fragmented ".repeat(50);
+        let compressed = client_compress(&message);
+        let (first, rest) = compressed.split_at(compressed.len() / 2);
+
+        let mut header = Header::new(OpCode::Binary);
+        header.set_fin(false);
+        header.set_rsv1(true);
+        let mut data = first.to_vec();
+        extension.decode(&mut header, &mut data).unwrap();
+        assert_eq!(data, first);
+
+        let mut header = Header::new(OpCode::Continue);
+        data.extend_from_slice(rest);
+        extension.decode(&mut header, &mut data).unwrap();
+        assert_eq!(data, message);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let (mut extension, _) = negotiate(NAME, 0, 6).unwrap();
+        let mut header = Header::new(OpCode::Text);
+        header.set_rsv1(true);
+        let mut data = vec![0xff; 16];
+        assert!(extension.decode(&mut header, &mut data).is_err());
+
+        let (mut extension, _) = negotiate(NAME, 0, 6).unwrap();
+        extension.max_size = 1 << 20;
+        let mut header = Header::new(OpCode::Binary);
+        header.set_rsv1(true);
+        let mut bomb = client_compress(&vec![0; (1 << 20) + 1]);
+        let error = extension.decode(&mut header, &mut bomb).unwrap_err();
+        assert!(error.to_string().contains("size limit"), "{}", error);
+    }
+}
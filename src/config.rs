@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use lazy_static::lazy_static;
+use log::warn;
+
+/// Server settings, read once from `CHAT_*` environment variables.
+pub struct Config {
+    /// Address the HTTP server listens on; port 0 picks a free one.
+    pub address: SocketAddr,
+    /// Let WebSocket clients negotiate permessage-deflate.
+    pub ws_compression: bool,
+    /// Messages smaller than this many bytes are sent uncompressed.
+    pub ws_compression_threshold: usize,
+    /// DEFLATE level, 0 (none) to 9 (best).
+    pub ws_compression_level: u32,
+    /// Queued direct messages and mentions are dropped after this many days.
+    pub offline_queue_days: i64,
+    /// Largest file accepted, in bytes.
+    pub max_file_size: u64,
+    /// Bytes of uploads each user may have stored.
+    pub user_quota: u64,
+    /// Bytes of uploads the server stores in total.
+    pub global_quota: u64,
+    /// Sniffed MIME types that may be uploaded, e.g. `image/*`. Empty allows
+    /// everything not in `mime_deny`.
+    pub mime_allow: Vec<String>,
+    /// Sniffed MIME types that are always rejected.
+    pub mime_deny: Vec<String>,
+    /// Key for signed download links. Random unless `CHAT_DOWNLOAD_SECRET`
+    /// is set, so by default links stop working when the server restarts.
+    pub download_secret: Vec<u8>,
+    /// How long signed download links stay valid, in seconds.
+    pub download_link_ttl: i64,
+    /// Remove EXIF and XMP metadata from uploaded JPEG, PNG and WebP images
+    /// unless the uploader asks to keep it.
+    pub strip_metadata: bool,
+    /// Where uploads are stored: `local` (the uploads directory) or `s3`.
+    pub storage: String,
+    /// Base URL of the S3-compatible service, e.g. `http://localhost:9000`.
+    pub s3_endpoint: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    /// Malware scanner run over uploads before they are shared: `none` or
+    /// `clamd`.
+    pub scanner: String,
+    /// `host:port` or Unix socket path of clamd.
+    pub clamd_address: String,
+    /// Users who may delete anyone's files.
+    pub moderators: Vec<String>,
+    /// Days files are kept, by room; `*` applies to rooms not listed. Rooms
+    /// without an entry keep files forever.
+    pub file_retention_days: HashMap<String, i64>,
+    /// Path of the SQLite database.
+    pub database_path: String,
+    /// Most database connections open at once, i.e. how many queries can
+    /// run concurrently.
+    pub database_pool_size: u32,
+}
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::from_env();
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid value {:?} for {}", value, name);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Comma separated list, `default` if the variable is not set.
+fn env_list(name: &str, default: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|item| item.trim().to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Comma separated `key=value` pairs. A bare value is taken as `*=value`.
+fn env_map<T: FromStr>(name: &str) -> HashMap<String, T> {
+    let value = std::env::var(name).unwrap_or_default();
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| {
+            let (key, value) = item.split_once('=').unwrap_or(("*", item));
+            match value.trim().parse() {
+                Ok(value) => Some((key.trim().to_string(), value)),
+                Err(_) => {
+                    warn!("Ignoring invalid entry {:?} in {}", item, name);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+impl Config {
+    /// How many days files shared in `room` are kept, `None` for ever.
+    pub fn retention_days(&self, room: &str) -> Option<i64> {
+        self.file_retention_days
+            .get(room)
+            .or_else(|| self.file_retention_days.get("*"))
+            .copied()
+    }
+
+    fn from_env() -> Config {
+        Config {
+            address: env_or("CHAT_ADDRESS", SocketAddr::from(([127, 0, 0, 1], 3030))),
+            ws_compression: env_or("CHAT_WS_COMPRESSION", true),
+            ws_compression_threshold: env_or("CHAT_WS_COMPRESSION_THRESHOLD", 256),
+            ws_compression_level: env_or("CHAT_WS_COMPRESSION_LEVEL", 6).min(9),
+            offline_queue_days: env_or("CHAT_OFFLINE_QUEUE_DAYS", 30),
+            max_file_size: env_or("CHAT_MAX_FILE_SIZE", 100 << 20),
+            user_quota: env_or("CHAT_USER_QUOTA", 1 << 30),
+            global_quota: env_or("CHAT_GLOBAL_QUOTA", 50 << 30),
+            mime_allow: env_list("CHAT_MIME_ALLOW", ""),
+            mime_deny: env_list(
+                "CHAT_MIME_DENY",
+                "application/x-executable,application/vnd.microsoft.portable-executable,\
+                 application/x-mach-binary,application/vnd.android.dex",
+            ),
+            download_secret: std::env::var("CHAT_DOWNLOAD_SECRET")
+                .map(String::into_bytes)
+                .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec()),
+            download_link_ttl: env_or("CHAT_DOWNLOAD_LINK_TTL", 60 * 60),
+            strip_metadata: env_or("CHAT_STRIP_METADATA", false),
+            storage: env_or("CHAT_STORAGE", "local".to_string()).to_ascii_lowercase(),
+            s3_endpoint: env_or("CHAT_S3_ENDPOINT", String::new()),
+            s3_bucket: env_or("CHAT_S3_BUCKET", String::new()),
+            s3_region: env_or("CHAT_S3_REGION", "us-east-1".to_string()),
+            s3_access_key: env_or("CHAT_S3_ACCESS_KEY", String::new()),
+            s3_secret_key: env_or("CHAT_S3_SECRET_KEY", String::new()),
+            scanner: env_or("CHAT_SCANNER", "none".to_string()).to_ascii_lowercase(),
+            clamd_address: env_or("CHAT_CLAMD_ADDRESS", "127.0.0.1:3310".to_string()),
+            // Not lowercased like `env_list`, usernames are case sensitive.
+            moderators: std::env::var("CHAT_MODERATORS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            file_retention_days: env_map("CHAT_FILE_RETENTION_DAYS"),
+            database_path: env_or("CHAT_DATABASE_PATH", "users.db".to_string()),
+            database_pool_size: env_or("CHAT_DATABASE_POOL_SIZE", 8).max(1),
+        }
+    }
+}
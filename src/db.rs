@@ -0,0 +1,130 @@
+use std::time::Duration;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+
+/// How long a connection waits for another one's write lock before giving
+/// up with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a query waits for a free connection.
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A pool of connections to the database. Queries go through [`Db::run`],
+/// which runs them on tokio's blocking threads so they never stall the
+/// async workers. The database is in WAL mode, so readers do not wait for
+/// writers or each other.
+#[derive(Clone)]
+pub struct Db {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+impl Db {
+    pub fn open(path: &str, size: u32) -> Result<Db, r2d2::Error> {
+        Db::open_with_timeout(path, size, CHECKOUT_TIMEOUT)
+    }
+
+    fn open_with_timeout(path: &str, size: u32, checkout_timeout: Duration) -> Result<Db, r2d2::Error> {
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+            conn.pragma_update(None, "synchronous", "NORMAL")
+        });
+        let pool = r2d2::Pool::builder()
+            .max_size(size)
+            .min_idle(Some(1))
+            .connection_timeout(checkout_timeout)
+            .build(manager)?;
+        Ok(Db { pool })
+    }
+
+    /// Runs `f` with a connection from the pool on a blocking thread.
+    /// Not getting a connection in time is reported as `SQLITE_BUSY`.
+    pub async fn run<T, F>(&self, f: F) -> rusqlite::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|e| {
+                rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                    Some(e.to_string()),
+                )
+            })?;
+            f(&mut conn)
+        });
+        match task.await {
+            Ok(result) => result,
+            // Let a panic in `f` take down the caller, as it would have inline.
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// A migrated database in a temporary directory, which is deleted when
+/// dropped.
+#[cfg(test)]
+pub async fn open_temp() -> (tempfile::TempDir, Db) {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let path = dir.path().join("test.db");
+    let db = Db::open(path.to_str().expect("UTF-8 path"), 4).expect("open database");
+    crate::migrations::run(&db, false).await.expect("migrate");
+    (dir, db)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_an_exhausted_pool_as_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = Db::open_with_timeout(path.to_str().unwrap(), 1, Duration::from_millis(100)).unwrap();
+
+        // Holds the only connection until told to let go.
+        let (checked_out, wait_for_checkout) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let holder = tokio::spawn({
+            let db = db.clone();
+            async move {
+                db.run(move |_| {
+                    checked_out.send(()).unwrap();
+                    let _ = released.recv();
+                    Ok(())
+                })
+                .await
+            }
+        });
+        tokio::task::spawn_blocking(move || wait_for_checkout.recv().unwrap()).await.unwrap();
+
+        match db.run(|_| Ok(())).await {
+            Err(rusqlite::Error::SqliteFailure(e, Some(_))) => assert_eq!(e.code, rusqlite::ErrorCode::DatabaseBusy),
+            other => panic!("expected SQLITE_BUSY, got {:?}", other),
+        }
+
+        release.send(()).unwrap();
+        holder.await.unwrap().unwrap();
+        let answer: i64 = db.run(|conn| conn.query_row("SELECT 42", [], |row| row.get(0))).await.unwrap();
+        assert_eq!(answer, 42);
+    }
+
+    #[tokio::test]
+    async fn opens_in_wal_mode() {
+        let (_dir, db) = open_temp().await;
+        let mode: String = db
+            .run(|conn| conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+}
@@ -1,63 +1,1552 @@
+use std::borrow::Cow;
 use std::convert::Infallible;
 use tokio::fs;
-use std::path::PathBuf;
-use warp::http::StatusCode;
-use mime_guess;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::path::{Path, PathBuf};
+use bytes::Buf;
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::http::{header, HeaderMap, Response, StatusCode};
+use warp::hyper::Body;
+use warp::multipart::{FormData, Part};
+use uuid::Uuid;
 
-pub async fn handle_file_download(
-    file_id: String,
-) -> Result<Box<dyn warp::Reply + Send>, Infallible> {
-    let uploads_dir = PathBuf::from("./uploads");
-    let entries = match fs::read_dir(&uploads_dir).await {
-        Ok(entries) => entries,
-        Err(_) => {
-            return Ok(Box::new(warp::reply::with_status(
-                "Uploads directory not found",
-                StatusCode::NOT_FOUND,
-            )))
+use crate::api::UploadedFile;
+use crate::auth::{self, json_message};
+use crate::chat::{self, Clients, LOBBY_ROOM};
+use crate::config::CONFIG;
+use crate::db::Db;
+use crate::limits::{self, Rejection};
+use crate::metadata;
+use crate::scanning::{self, Verdict};
+use crate::protocol::{ErrorCode, ImagePreview};
+use crate::storage::STORAGE;
+use crate::thumbnails::{self, ImageInfo};
+
+/// Files are stored under their id alone, see `storage`; the name the
+/// uploader gave is only kept as metadata.
+pub const UPLOADS_DIR: &str = "./uploads";
+/// Partial uploads live here until they are complete.
+pub const TEMP_DIR: &str = "./uploads/tmp";
+/// Longest original filename kept, in bytes.
+const MAX_FILENAME_LEN: usize = 255;
+
+/// Turns a client supplied filename into something safe to store and show:
+/// no directories, no control characters, no leading dots and a bounded
+/// length. Falls back to "file" if nothing is left.
+pub fn sanitize_filename(filename: &str) -> String {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base.chars().filter(|c| !c.is_control()).collect();
+    let cleaned = cleaned.trim().trim_start_matches('.').trim();
+
+    let mut end = cleaned.len().min(MAX_FILENAME_LEN);
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+    match &cleaned[..end] {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Builds an RFC 6266 `Content-Disposition` value: an ASCII-only
+/// `filename` for old clients and the exact name, percent-encoded as UTF-8,
+/// in `filename*`.
+pub fn content_disposition(disposition: &str, filename: &str) -> String {
+    let filename = sanitize_filename(filename);
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        // attr-char from RFC 5987, everything else is percent-encoded.
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
+}
+
+/// Why an upload could not be stored.
+#[derive(Debug)]
+pub enum SaveError {
+    Rejected(Rejection),
+    /// The scanner found the named signature; the file was quarantined.
+    Infected(String),
+    Io(std::io::Error),
+}
+
+const INFECTED_MESSAGE: &str = "The file was flagged by the malware scanner and was not shared.";
+
+impl From<Rejection> for SaveError {
+    fn from(rejection: Rejection) -> Self {
+        SaveError::Rejected(rejection)
+    }
+}
+
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SaveError::Rejected(rejection) => write!(f, "rejected: {}", rejection),
+            SaveError::Infected(signature) => write!(f, "infected: {}", signature),
+            SaveError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl SaveError {
+    /// The error code and message to send back over the chat protocol.
+    pub fn to_frame_error(&self) -> (ErrorCode, String) {
+        match self {
+            SaveError::Rejected(rejection) => (rejection.code(), rejection.to_string()),
+            SaveError::Infected(_) => (ErrorCode::FileInfected, INFECTED_MESSAGE.into()),
+            SaveError::Io(_) => (ErrorCode::Internal, "Failed to save file.".into()),
+        }
+    }
+
+    pub fn to_reply(&self) -> Box<dyn warp::Reply> {
+        match self {
+            SaveError::Rejected(rejection) => json_message(&rejection.to_string(), rejection.status()),
+            SaveError::Infected(_) => json_message(INFECTED_MESSAGE, StatusCode::UNPROCESSABLE_ENTITY),
+            SaveError::Io(_) => json_message("Failed to save file.", StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// A row of the `files` table.
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    pub id: String,
+    /// Sanitized name the file was uploaded under.
+    pub original_name: String,
+    pub size: u64,
+    /// Sniffed from the content, not taken from the client.
+    pub mime: String,
+    /// `None` for files that predate the table and could not be attributed.
+    pub uploader: Option<String>,
+    pub room: String,
+    /// Hex SHA-256 of the content.
+    pub sha256: String,
+    pub created_at: i64,
+}
+
+async fn insert_file(db: &Db, file: &FileRecord) -> rusqlite::Result<()> {
+    let file = file.clone();
+    db.run(move |conn| {
+        conn.execute(
+            "INSERT INTO files (id, original_name, size, mime, uploader, room, sha256, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![
+                file.id,
+                file.original_name,
+                file.size,
+                file.mime,
+                file.uploader,
+                file.room,
+                file.sha256,
+                file.created_at
+            ],
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+/// Looks a file up by its exact id.
+pub async fn find_file(db: &Db, file_id: &str) -> Option<FileRecord> {
+    let file_id = file_id.to_string();
+    db.run(move |conn| {
+        conn.query_row(
+            "SELECT id, original_name, size, mime, uploader, room, sha256, created_at FROM files WHERE id = ?1",
+            rusqlite::params![file_id],
+            |row| {
+                Ok(FileRecord {
+                    id: row.get(0)?,
+                    original_name: row.get(1)?,
+                    size: row.get(2)?,
+                    mime: row.get(3)?,
+                    uploader: row.get(4)?,
+                    room: row.get(5)?,
+                    sha256: row.get(6)?,
+                    created_at: row.get(7)?,
+                })
+            },
+        )
+    })
+    .await
+    .ok()
+}
+
+/// Adds a reference to the stored blob with this content, if there is one.
+fn reference_blob(conn: &Connection, sha256: &str, size: u64) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        "UPDATE blobs SET refcount = refcount + 1 WHERE sha256 = ?1 AND size = ?2",
+        params![sha256, size],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Makes the file at `path` available as the blob `sha256` and takes a
+/// reference to it. If the same content is stored already the file is
+/// simply dropped. `path` is gone afterwards unless an error is returned.
+/// New images get a thumbnail.
+async fn put_blob(db: &Db, sha256: &str, size: u64, mime: &str, path: &Path) -> std::io::Result<()> {
+    let hash = sha256.to_string();
+    let referenced = db.run(move |conn| reference_blob(conn, &hash, size)).await;
+    if referenced.map_err(std::io::Error::other)? {
+        let _ = fs::remove_file(path).await;
+        return Ok(());
+    }
+    let image = match thumbnails::is_image(mime) {
+        true => thumbnails::analyze(path.to_path_buf()).await,
+        false => None,
+    };
+
+    // Every stored copy gets a key of its own, so a blob that is being
+    // deleted never shares its key with one that is being added.
+    let key = Uuid::new_v4().to_string();
+    STORAGE.put_file(&key, path).await?;
+    let (hash, blob_key) = (sha256.to_string(), key.clone());
+    let added = db
+        .run(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO blobs (sha256, storage_key, size, refcount, created_at)
+                 VALUES (?1, ?2, ?3, 1, ?4)",
+                params![hash, blob_key, size, crate::db::now()],
+            )?;
+            match inserted {
+                1 => Ok(true),
+                // Someone stored the same content while this copy was uploaded.
+                _ => reference_blob(conn, &hash, size).map(|_| false),
+            }
+        })
+        .await;
+    match added {
+        Ok(true) => {
+            if let Some(image) = image {
+                save_image(db, sha256, image).await;
+            }
+            Ok(())
+        }
+        Ok(false) => {
+            let _ = STORAGE.delete(&key).await;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = STORAGE.delete(&key).await;
+            Err(std::io::Error::other(e))
+        }
+    }
+}
+
+/// Stores the thumbnail of a new blob and records its dimensions. Failing
+/// here only costs the preview, so errors are just logged.
+async fn save_image(db: &Db, sha256: &str, image: ImageInfo) {
+    let mut thumbnail_id = None;
+    let mut thumbnail_mime = None;
+    if let Some((data, mime)) = image.thumbnail {
+        let id = Uuid::new_v4().to_string();
+        let temp_path = PathBuf::from(TEMP_DIR).join(format!("{}.part", id));
+        let stored = async {
+            fs::write(&temp_path, &data).await?;
+            STORAGE.put_file(&id, &temp_path).await
+        };
+        match stored.await {
+            Ok(()) => {
+                thumbnail_id = Some(id);
+                thumbnail_mime = Some(mime);
+            }
+            Err(e) => {
+                eprintln!("Failed to store thumbnail of {}: {}", sha256, e);
+                let _ = fs::remove_file(&temp_path).await;
+            }
+        }
+    }
+
+    let (hash, (width, height), thumbnail) = (sha256.to_string(), (image.width, image.height), thumbnail_id.clone());
+    let inserted = db
+        .run(move |conn| {
+            conn.execute(
+                "INSERT INTO images (sha256, width, height, thumbnail_id, thumbnail_mime) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![hash, width, height, thumbnail, thumbnail_mime],
+            )
+        })
+        .await;
+    if let Err(e) = inserted {
+        eprintln!("Failed to record image {}: {}", sha256, e);
+        if let Some(id) = thumbnail_id {
+            let _ = STORAGE.delete(&id).await;
+        }
+    }
+}
+
+/// Drops one reference to the blob `sha256`, deleting it and its thumbnail
+/// from storage along with the last one.
+pub async fn release_blob(db: &Db, sha256: &str) {
+    let hash = sha256.to_string();
+    let keys = db
+        .run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("UPDATE blobs SET refcount = refcount - 1 WHERE sha256 = ?1", params![hash])?;
+            let keys = delete_blob_row(&tx, &hash, "refcount <= 0")?;
+            tx.commit()?;
+            Ok(keys)
+        })
+        .await;
+    match keys {
+        Ok(keys) => delete_blob_objects(sha256, keys).await,
+        Err(e) => eprintln!("Failed to release blob {}: {}", sha256, e),
+    }
+}
+
+/// Deletes the blob `sha256` if no file refers to it any more, whatever its
+/// refcount says. Returns whether it did.
+pub async fn remove_orphan_blob(db: &Db, sha256: &str) -> bool {
+    let hash = sha256.to_string();
+    let keys = db
+        .run(move |conn| {
+            let tx = conn.transaction()?;
+            let keys = delete_blob_row(
+                &tx,
+                &hash,
+                "NOT EXISTS (SELECT 1 FROM files WHERE files.sha256 = blobs.sha256)",
+            )?;
+            tx.commit()?;
+            Ok(keys)
+        })
+        .await;
+    match keys {
+        Ok(keys) if keys.is_empty() => false,
+        Ok(keys) => {
+            delete_blob_objects(sha256, keys).await;
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to remove orphan blob {}: {}", sha256, e);
+            false
+        }
+    }
+}
+
+/// Deletes the row of the blob `sha256` and of its image details if
+/// `condition` holds, returning the storage keys of the blob and its
+/// thumbnail, or nothing if it did not.
+fn delete_blob_row(conn: &Connection, sha256: &str, condition: &str) -> rusqlite::Result<Vec<String>> {
+    let key: Option<String> = conn
+        .query_row(
+            &format!("SELECT storage_key FROM blobs WHERE sha256 = ?1 AND {}", condition),
+            params![sha256],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(Vec::new()),
+    };
+    // The image row refers to the blob, so it has to go first.
+    let thumbnail: Option<String> = conn
+        .query_row(
+            "DELETE FROM images WHERE sha256 = ?1 RETURNING thumbnail_id",
+            params![sha256],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    conn.execute("DELETE FROM blobs WHERE sha256 = ?1", params![sha256])?;
+    Ok(std::iter::once(key).chain(thumbnail).collect())
+}
+
+async fn delete_blob_objects(sha256: &str, keys: Vec<String>) {
+    for key in keys {
+        if let Err(e) = STORAGE.delete(&key).await {
+            eprintln!("Failed to delete blob {} ({}): {}", sha256, key, e);
+        }
+    }
+}
+
+/// What `delete_file` removed.
+pub struct DeletedFile {
+    pub room: String,
+    /// The message that announced the file, now marked deleted.
+    pub message_id: Option<i64>,
+    pub deleted_at: i64,
+}
+
+/// Deletes the file `file_id`: its row, its reference to the blob and the
+/// quota it used, and marks the message that announced it as deleted by
+/// `deleted_by` (`None` for the retention policy). Returns `None` if there
+/// is no such file.
+pub async fn delete_file(db: &Db, file_id: &str, deleted_by: Option<&str>) -> rusqlite::Result<Option<DeletedFile>> {
+    let deleted_at = crate::db::now();
+    let (file_id, deleted_by) = (file_id.to_string(), deleted_by.map(String::from));
+    let deleted = db
+        .run(move |conn| {
+            let tx = conn.transaction()?;
+            let file: Option<(Option<String>, u64, String, String)> = tx
+                .query_row(
+                    "DELETE FROM files WHERE id = ?1 RETURNING uploader, size, sha256, room",
+                    params![file_id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .optional()?;
+            let message_id = match &file {
+                Some(_) => tx
+                    .query_row(
+                        "UPDATE messages SET deleted_at = ?2, deleted_by = ?3
+                         WHERE kind = 'file' AND file_id = ?1 AND deleted_at IS NULL RETURNING id",
+                        params![file_id, deleted_at, deleted_by],
+                        |row| row.get(0),
+                    )
+                    .optional()?,
+                None => None,
+            };
+            tx.commit()?;
+            Ok(file.map(|file| (file, message_id)))
+        })
+        .await?;
+
+    let ((uploader, size, sha256, room), message_id) = match deleted {
+        Some(deleted) => deleted,
+        None => return Ok(None),
+    };
+    if let Some(uploader) = uploader {
+        limits::refund(db, &uploader, size).await;
+    }
+    release_blob(db, &sha256).await;
+    Ok(Some(DeletedFile {
+        room,
+        message_id,
+        deleted_at,
+    }))
+}
+
+/// Image details of the file `file_id`, `None` if it is not an image.
+pub async fn find_image(db: &Db, file_id: &str) -> Option<ImagePreview> {
+    let file_id = file_id.to_string();
+    db.run(move |conn| {
+        conn.query_row(
+            "SELECT images.width, images.height, images.thumbnail_id
+             FROM files JOIN images ON images.sha256 = files.sha256 WHERE files.id = ?1",
+            params![file_id],
+            |row| {
+                Ok(ImagePreview {
+                    width: row.get(0)?,
+                    height: row.get(1)?,
+                    thumbnail_id: row.get(2)?,
+                })
+            },
+        )
+    })
+    .await
+    .ok()
+}
+
+/// The storage key holding the content with this hash.
+async fn blob_key(db: &Db, sha256: &str) -> Option<String> {
+    let sha256 = sha256.to_string();
+    db.run(move |conn| {
+        conn.query_row(
+            "SELECT storage_key FROM blobs WHERE sha256 = ?1",
+            params![sha256],
+            |row| row.get(0),
+        )
+    })
+    .await
+    .ok()
+}
+
+/// Whether `key` is already in use in storage, by a blob or a thumbnail.
+/// Both are stored under bare UUIDs, just like indexed legacy uploads.
+async fn is_stored_key(db: &Db, key: &str) -> bool {
+    let key = key.to_string();
+    db.run(move |conn| {
+        conn.query_row(
+            "SELECT 1 FROM blobs WHERE storage_key = ?1
+             UNION ALL SELECT 1 FROM images WHERE thumbnail_id = ?1",
+            params![key],
+            |_| Ok(()),
+        )
+    })
+    .await
+    .is_ok()
+}
+
+/// The sniffed type of content that has already been shared in `room`, if
+/// any. A client that announces such a hash does not need to send the file:
+/// everyone in the room can download it anyway, so knowing the hash gives
+/// nothing away.
+pub async fn known_content(db: &Db, sha256: &str, size: u64, room: &str) -> Option<String> {
+    let (sha256, room) = (sha256.to_ascii_lowercase(), room.to_string());
+    db.run(move |conn| {
+        conn.query_row(
+            "SELECT files.mime FROM files JOIN blobs ON blobs.sha256 = files.sha256
+             WHERE files.sha256 = ?1 AND files.size = ?2 AND files.room = ?3 LIMIT 1",
+            params![sha256, size, room],
+            |row| row.get(0),
+        )
+    })
+    .await
+    .ok()
+}
+
+/// Records a file whose blob reference has just been taken, giving the
+/// reference back if the row cannot be written.
+async fn commit_file(db: &Db, file: FileRecord) -> Result<String, SaveError> {
+    if let Err(e) = insert_file(db, &file).await {
+        if let Some(uploader) = &file.uploader {
+            limits::refund(db, uploader, file.size).await;
         }
+        release_blob(db, &file.sha256).await;
+        return Err(SaveError::Io(std::io::Error::other(e)));
+    }
+    Ok(file.id)
+}
+
+/// Checks an uploaded file against the type filters and `uploader`'s quota,
+/// puts it into storage and returns its new id. `strip_metadata` is the
+/// uploader's choice whether to remove image metadata, see
+/// `metadata::applies`.
+pub async fn save_upload(
+    db: &Db,
+    uploader: &str,
+    filename: &str,
+    data: &[u8],
+    strip_metadata: Option<bool>,
+) -> Result<String, SaveError> {
+    let mime = limits::check_type(data)?;
+    let data = match metadata::applies(&mime, strip_metadata) {
+        true => Cow::Owned(metadata::strip(&mime, data).map_err(Rejection::UnreadableImage)?),
+        false => Cow::Borrowed(data),
     };
+    let data = data.as_ref();
+    limits::charge(db, uploader, data.len() as u64).await?;
+
+    let file_id = Uuid::new_v4().to_string();
+    let sha256 = hex::encode(Sha256::digest(data));
+    let temp_path = PathBuf::from(TEMP_DIR).join(format!("{}.part", file_id));
+
+    let written = async {
+        fs::create_dir_all(TEMP_DIR).await?;
+        fs::write(&temp_path, data).await?;
+        scan_upload(db, uploader, filename, &sha256, &temp_path).await?;
+        put_blob(db, &sha256, data.len() as u64, &mime, &temp_path).await?;
+        Ok(())
+    };
+    if let Err(e) = written.await {
+        limits::refund(db, uploader, data.len() as u64).await;
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+
+    commit_file(
+        db,
+        FileRecord {
+            id: file_id,
+            original_name: sanitize_filename(filename),
+            size: data.len() as u64,
+            mime,
+            uploader: Some(uploader.to_string()),
+            room: LOBBY_ROOM.to_string(),
+            sha256,
+            created_at: crate::db::now(),
+        },
+    )
+    .await
+}
+
+/// Runs the configured scanner, if any, over a received upload. An infected
+/// file is moved to quarantine. Uploads are refused while the scanner
+/// cannot be reached.
+async fn scan_upload(db: &Db, uploader: &str, filename: &str, sha256: &str, path: &Path) -> Result<(), SaveError> {
+    let scanner = match scanning::SCANNER.as_ref() {
+        Some(scanner) => scanner,
+        None => return Ok(()),
+    };
+    match scanner.scan(path).await {
+        Ok(Verdict::Clean) => Ok(()),
+        Ok(Verdict::Infected(signature)) => {
+            let filename = sanitize_filename(filename);
+            scanning::quarantine(db, uploader, &filename, sha256, &signature, path).await?;
+            Err(SaveError::Infected(signature))
+        }
+        Err(e) => {
+            eprintln!("Failed to scan {} from {}: {}", filename, uploader, e);
+            Err(Rejection::ScanFailed.into())
+        }
+    }
+}
+
+async fn read_head(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(limits::SNIFF_LEN);
+    fs::File::open(path)
+        .await?
+        .take(limits::SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(head)
+}
+
+/// Like `save_upload` for a file that has been fully received into
+/// `temp_path`, which is moved into storage. `sha256` is the
+/// hash the caller computed while receiving it. The temp file is left in
+/// place if the upload is rejected.
+pub async fn store_file(
+    db: &Db,
+    uploader: &str,
+    filename: &str,
+    temp_path: &Path,
+    sha256: &str,
+    strip_metadata: Option<bool>,
+) -> Result<String, SaveError> {
+    let mime = limits::check_type(&read_head(temp_path).await?)?;
+    let mut sha256 = sha256.to_string();
+    if metadata::applies(&mime, strip_metadata) {
+        let original = fs::read(temp_path).await?;
+        let stripped = metadata::strip(&mime, &original).map_err(Rejection::UnreadableImage)?;
+        if stripped != original {
+            fs::write(temp_path, &stripped).await?;
+            sha256 = hex::encode(Sha256::digest(&stripped));
+        }
+    }
+    let size = fs::metadata(temp_path).await?.len();
+    limits::charge(db, uploader, size).await?;
+
+    if let Err(e) = scan_upload(db, uploader, filename, &sha256, temp_path).await {
+        limits::refund(db, uploader, size).await;
+        return Err(e);
+    }
+    if let Err(e) = put_blob(db, &sha256, size, &mime, temp_path).await {
+        limits::refund(db, uploader, size).await;
+        return Err(e.into());
+    }
+
+    commit_file(
+        db,
+        FileRecord {
+            id: Uuid::new_v4().to_string(),
+            original_name: sanitize_filename(filename),
+            size,
+            mime,
+            uploader: Some(uploader.to_string()),
+            room: LOBBY_ROOM.to_string(),
+            sha256,
+            created_at: crate::db::now(),
+        },
+    )
+    .await
+}
+
+/// Like `store_file` for content that was not sent because the client
+/// announced a hash `known_content` recognised. Returns `None` if that
+/// content has been deleted since, in which case it has to be sent after
+/// all.
+pub async fn store_known_file(
+    db: &Db,
+    uploader: &str,
+    filename: &str,
+    sha256: &str,
+    size: u64,
+) -> Result<Option<String>, SaveError> {
+    let mime = match known_content(db, sha256, size, LOBBY_ROOM).await {
+        Some(mime) => mime,
+        None => return Ok(None),
+    };
+    limits::check_mime(&mime)?;
+    limits::charge(db, uploader, size).await?;
+    let hash = sha256.to_string();
+    let referenced = db.run(move |conn| reference_blob(conn, &hash, size)).await;
+    match referenced {
+        Ok(true) => {}
+        Ok(false) => {
+            limits::refund(db, uploader, size).await;
+            return Ok(None);
+        }
+        Err(e) => {
+            limits::refund(db, uploader, size).await;
+            return Err(SaveError::Io(std::io::Error::other(e)));
+        }
+    }
+
+    commit_file(
+        db,
+        FileRecord {
+            id: Uuid::new_v4().to_string(),
+            original_name: sanitize_filename(filename),
+            size,
+            mime,
+            uploader: Some(uploader.to_string()),
+            room: LOBBY_ROOM.to_string(),
+            sha256: sha256.to_string(),
+            created_at: crate::db::now(),
+        },
+    )
+    .await
+    .map(Some)
+}
+
+async fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Adds files stored before the `files` table existed. Old uploads were
+/// named `{id}_{original name}` in the uploads directory; they are moved
+/// into storage under their bare id. Run at startup, files that are already
+/// indexed are skipped.
+pub async fn index_existing_files(db: &Db) -> std::io::Result<usize> {
+    index_blobs(db).await?;
+
+    let mut entries = match fs::read_dir(UPLOADS_DIR).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut indexed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // A UUID is always 36 characters.
+        let (file_id, original) = match name.get(..36).map(|id| (id, &name[36..])) {
+            Some((id, rest)) if Uuid::parse_str(id).is_ok() && (rest.is_empty() || rest.starts_with('_')) => {
+                (id.to_string(), rest.strip_prefix('_').map(String::from))
+            }
+            _ => {
+                eprintln!("Not indexing unexpected file {} in {}", name, UPLOADS_DIR);
+                continue;
+            }
+        };
+        if find_file(db, &file_id).await.is_some() || is_stored_key(db, &file_id).await {
+            continue;
+        }
+
+        // The file message, if there is one, knows who sent it and when.
+        let id = file_id.clone();
+        let message: Option<(String, String, Option<String>, i64)> = db
+            .run(move |conn| {
+                conn.query_row(
+                    "SELECT sender, room, filename, created_at FROM messages WHERE kind = 'file' AND file_id = ?1",
+                    rusqlite::params![id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+            })
+            .await
+            .ok();
 
-    let mut entries = entries;
-    while let Some(entry) = entries.next_entry().await.unwrap_or(None) {
         let path = entry.path();
-        if path.is_file() {
-            if let Some(filename) = path.file_name() {
-                let filename_str = filename.to_string_lossy();
-                if filename_str.starts_with(&file_id) {
-                    let content = match tokio::fs::read(&path).await {
-                        Ok(c) => c,
-                        Err(_) => {
-                            return Ok(Box::new(warp::reply::with_status(
-                                "Error reading file",
-                                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                            )))
-                        }
-                    };
-                    let mime_type = mime_guess::from_path(&path).first_or_octet_stream();
-
-                    let disposition = format!(
-                        "attachment; filename=\"{}\"",
-                        &filename_str[file_id.len() + 1..]
-                    );
-
-                    let response = warp::reply::with_header(
-                        warp::reply::with_header(
-                            content,
-                            "Content-Type",
-                            mime_type.to_string(),
-                        ),
-                        "Content-Disposition",
-                        disposition,
-                    );
-                    return Ok(Box::new(response));
+        let metadata = fs::metadata(&path).await?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or_else(crate::db::now);
+
+        let original_name = message
+            .as_ref()
+            .and_then(|(_, _, filename, _)| filename.clone())
+            .or(original)
+            .unwrap_or_else(|| file_id.clone());
+        let record = FileRecord {
+            original_name: sanitize_filename(&original_name),
+            size: metadata.len(),
+            mime: limits::sniff(&read_head(&path).await?),
+            uploader: message.as_ref().map(|(sender, ..)| sender.clone()),
+            room: message.as_ref().map_or_else(|| LOBBY_ROOM.to_string(), |(_, room, ..)| room.clone()),
+            sha256: hash_file(&path).await?,
+            created_at: message.as_ref().map_or(modified, |(.., created_at)| *created_at),
+            id: file_id,
+        };
+        put_blob(db, &record.sha256, record.size, &record.mime, &path).await?;
+        insert_file(db, &record).await.map_err(std::io::Error::other)?;
+        indexed += 1;
+    }
+    Ok(indexed)
+}
+
+/// Files stored before uploads were deduplicated live under their own id.
+/// Makes each of them a blob, or drops it if the same content is already
+/// stored.
+async fn index_blobs(db: &Db) -> std::io::Result<()> {
+    let unindexed: Vec<(String, String, u64)> = db
+        .run(|conn| {
+            conn.prepare(
+                "SELECT id, sha256, size FROM files
+                 WHERE sha256 NOT IN (SELECT sha256 FROM blobs) ORDER BY created_at",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect()
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let mut duplicates = 0;
+    for (file_id, sha256, size) in unindexed {
+        let (hash, key) = (sha256.clone(), file_id.clone());
+        let referenced = db
+            .run(move |conn| {
+                let referenced = reference_blob(conn, &hash, size)?;
+                if !referenced {
+                    conn.execute(
+                        "INSERT INTO blobs (sha256, storage_key, size, refcount, created_at)
+                         VALUES (?1, ?2, ?3, 1, ?4)",
+                        params![hash, key, size, crate::db::now()],
+                    )?;
                 }
+                Ok(referenced)
+            })
+            .await;
+        if referenced.map_err(std::io::Error::other)? {
+            STORAGE.delete(&file_id).await?;
+            duplicates += 1;
+        }
+    }
+    if duplicates > 0 {
+        println!("Removed {} duplicate copies of stored files", duplicates);
+    }
+    Ok(())
+}
+
+/// Streams one multipart part into a new temp file and returns its path and
+/// hash, giving up as soon as it goes over the size limit.
+async fn write_part_to_temp(part: Part) -> Result<(PathBuf, String), SaveError> {
+    fs::create_dir_all(TEMP_DIR).await?;
+    let temp_path = PathBuf::from(TEMP_DIR).join(format!("{}.part", Uuid::new_v4()));
+    let mut file = fs::File::create(&temp_path).await?;
+
+    let mut stream = part.stream();
+    let written = async {
+        let mut size = 0u64;
+        let mut hasher = Sha256::new();
+        while let Some(mut buf) = stream.try_next().await.map_err(std::io::Error::other)? {
+            size += buf.remaining() as u64;
+            limits::check_file_size(size)?;
+            while buf.has_remaining() {
+                let chunk = buf.chunk();
+                file.write_all(chunk).await?;
+                hasher.update(chunk);
+                let len = chunk.len();
+                buf.advance(len);
             }
         }
+        file.flush().await?;
+        Ok::<_, SaveError>(hex::encode(hasher.finalize()))
+    };
+    match written.await {
+        Ok(sha256) => Ok((temp_path, sha256)),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path).await;
+            Err(e)
+        }
     }
+}
+
+/// Query string of `POST /upload`.
+#[derive(Deserialize, Debug)]
+pub struct UploadQuery {
+    /// Overrides the server's `CHAT_STRIP_METADATA` setting for this file.
+    pub strip_metadata: Option<bool>,
+}
 
+/// `POST /upload`: takes a multipart form with a `file` part, streams it to
+/// disk and announces it in the lobby like a file sent over the WebSocket.
+/// Accepts a session cookie or a bot token.
+pub async fn handle_upload(
+    query: UploadQuery,
+    form: FormData,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    clients: Clients,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let identity = match auth::authenticate(&db, session_token.as_deref(), authorization.as_deref()).await {
+        Some(identity) => identity,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+    if !identity.can_access(LOBBY_ROOM) {
+        return Ok(json_message("Not allowed to post in this room.", StatusCode::FORBIDDEN));
+    }
+
+    let mut form = form;
+    let part = loop {
+        match form.try_next().await {
+            Ok(Some(part)) if part.name() == "file" => break part,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Ok(json_message("Missing \"file\" part.", StatusCode::BAD_REQUEST)),
+            Err(e) => {
+                return Ok(json_message(&format!("Invalid multipart body: {}", e), StatusCode::BAD_REQUEST))
+            }
+        }
+    };
+
+    let filename = match part.filename() {
+        Some(filename) if !filename.trim().is_empty() => filename.to_string(),
+        _ => return Ok(json_message("Filename cannot be empty.", StatusCode::BAD_REQUEST)),
+    };
+
+    let (temp_path, sha256) = match write_part_to_temp(part).await {
+        Ok(received) => received,
+        Err(e) => {
+            eprintln!("Failed to receive upload {} from {}: {}", filename, identity.name(), e);
+            return Ok(match e {
+                SaveError::Io(_) => json_message("Failed to receive file.", StatusCode::BAD_REQUEST),
+                rejected => rejected.to_reply(),
+            });
+        }
+    };
+    let file_id = match store_file(&db, identity.name(), &filename, &temp_path, &sha256, query.strip_metadata).await {
+        Ok(file_id) => file_id,
+        Err(e) => {
+            eprintln!("Failed to save upload {} from {}: {}", filename, identity.name(), e);
+            chat::notify_quarantined(&clients, identity.name(), &filename, &e);
+            let _ = fs::remove_file(&temp_path).await;
+            return Ok(e.to_reply());
+        }
+    };
+
+    let message_id = chat::publish_file(&clients, &db, identity.name(), filename, file_id.clone()).await;
     Ok(Box::new(warp::reply::with_status(
-        "File not found",
-        StatusCode::NOT_FOUND,
+        warp::reply::json(&UploadedFile { file_id, message_id }),
+        StatusCode::CREATED,
     )))
 }
+
+/// Query string of a signed download link.
+#[derive(Deserialize, Debug)]
+pub struct DownloadQuery {
+    pub expires: Option<i64>,
+    pub signature: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DownloadLink {
+    pub url: String,
+    pub expires_at: i64,
+}
+
+fn download_signature(file_id: &str, expires: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&CONFIG.download_secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(file_id.as_bytes());
+    mac.update(b".");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+/// A link to `file_id` that works without a session until `expires`.
+pub fn signed_download_url(file_id: &str, expires: i64) -> String {
+    let signature = hex::encode(download_signature(file_id, expires).finalize().into_bytes());
+    format!("/download/{}?expires={}&signature={}", file_id, expires, signature)
+}
+
+fn verify_download_signature(file_id: &str, expires: i64, signature: &str) -> bool {
+    match hex::decode(signature) {
+        // Constant time comparison.
+        Ok(signature) => download_signature(file_id, expires).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Who is downloading a file: the holder of a signed link to it, or a user
+/// or bot who may only download files shared in rooms they can see.
+enum Downloader {
+    SignedLink,
+    Identity(auth::Identity),
+}
+
+impl Downloader {
+    fn can_download(&self, file: &FileRecord) -> bool {
+        match self {
+            Downloader::SignedLink => true,
+            Downloader::Identity(identity) => identity.can_access(&file.room),
+        }
+    }
+}
+
+/// Checks the link or credentials of a download before the file is looked
+/// up, so callers cannot probe which file ids exist.
+async fn authorize_download(
+    db: &Db,
+    file_id: &str,
+    query: &DownloadQuery,
+    session_token: Option<&str>,
+    authorization: Option<&str>,
+) -> Result<Downloader, Box<dyn warp::Reply>> {
+    if let (Some(expires), Some(signature)) = (query.expires, query.signature.as_deref()) {
+        return match check_download_link(file_id, expires, signature, crate::db::now()) {
+            Ok(()) => Ok(Downloader::SignedLink),
+            Err(message) => Err(json_message(message, StatusCode::FORBIDDEN)),
+        };
+    }
+
+    match auth::authenticate(db, session_token, authorization).await {
+        Some(identity) => Ok(Downloader::Identity(identity)),
+        None => Err(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    }
+}
+
+fn check_download_link(file_id: &str, expires: i64, signature: &str, now: i64) -> Result<(), &'static str> {
+    if !verify_download_signature(file_id, expires, signature) {
+        return Err("Invalid download link.");
+    }
+    if expires < now {
+        return Err("Download link has expired.");
+    }
+    Ok(())
+}
+
+/// The file, if it exists and `downloader` may have it. Files in rooms the
+/// downloader cannot see are not found either.
+async fn find_downloadable(db: &Db, file_id: &str, downloader: &Downloader) -> Result<FileRecord, Box<dyn warp::Reply>> {
+    match find_file(db, file_id).await {
+        Some(file) if downloader.can_download(&file) => Ok(file),
+        _ => Err(json_message("File not found.", StatusCode::NOT_FOUND)),
+    }
+}
+
+/// The byte range a `Range` header asks for, resolved against the file size.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// Inclusive start and end offsets.
+    Partial(u64, u64),
+    /// No byte of the file lies in the range.
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range. Anything else, including multiple ranges,
+/// is ignored and the whole file is sent, which RFC 9110 allows.
+fn parse_range(header: &str, size: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let len: u64 = suffix.parse().ok()?;
+            if len == 0 || size == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(size.saturating_sub(len), size - 1)
+            }
+        }
+        (start, end) => {
+            let start: u64 = start.parse().ok()?;
+            let end: Option<u64> = match end {
+                "" => None,
+                end => Some(end.parse().ok()?),
+            };
+            if end.is_some_and(|end| end < start) {
+                return None;
+            }
+            if start >= size {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(start, end.map_or(size - 1, |end| end.min(size - 1)))
+            }
+        }
+    };
+    Some(range)
+}
+
+/// Whether an `If-None-Match` or `If-Range` value names `etag`. Weak
+/// validators match too, which is what `If-None-Match` calls for.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn http_date(timestamp: i64) -> String {
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(timestamp.max(0) as u64);
+    httpdate::fmt_http_date(time)
+}
+
+/// Whether the client's cached copy is current. `If-Modified-Since` only
+/// counts without `If-None-Match`, as RFC 9110 says.
+fn not_modified(if_none_match: Option<&str>, if_modified_since: Option<&str>, etag: &str, modified: i64) -> bool {
+    match if_none_match {
+        Some(if_none_match) => etag_matches(if_none_match, etag),
+        None => if_modified_since.is_some_and(|since| not_modified_since(since, modified)),
+    }
+}
+
+/// Whether the client's copy, dated by an HTTP date header, is current.
+fn not_modified_since(header: &str, modified: i64) -> bool {
+    match httpdate::parse_http_date(header.trim()) {
+        Ok(since) => since
+            .duration_since(std::time::UNIX_EPOCH)
+            .is_ok_and(|since| modified <= since.as_secs() as i64),
+        Err(_) => false,
+    }
+}
+
+/// `GET /download/{id}`: streams the file from storage. Supports single
+/// `Range` requests (with `If-Range`) and answers `If-None-Match` and
+/// `If-Modified-Since` with 304. Files never change once stored, so the
+/// content hash is a strong ETag.
+pub async fn handle_file_download(
+    file_id: String,
+    query: DownloadQuery,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    headers: HeaderMap,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let downloader =
+        match authorize_download(&db, &file_id, &query, session_token.as_deref(), authorization.as_deref()).await {
+            Ok(downloader) => downloader,
+            Err(reply) => return Ok(reply),
+        };
+    let file = match find_downloadable(&db, &file_id, &downloader).await {
+        Ok(file) => file,
+        Err(reply) => return Ok(reply),
+    };
+
+    let header = |name: header::HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+    let etag = format!("\"{}\"", file.sha256);
+    let last_modified = http_date(file.created_at);
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::CACHE_CONTROL, "private, no-cache")
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if not_modified(header(header::IF_NONE_MATCH), header(header::IF_MODIFIED_SINCE), &etag, file.created_at) {
+        return Ok(Box::new(response.status(StatusCode::NOT_MODIFIED).body(Body::empty())));
+    }
+
+    let size = file.size;
+    let key = match blob_key(&db, &file.sha256).await {
+        Some(key) => key,
+        None => {
+            eprintln!("File {} has no stored content", file.id);
+            return Ok(json_message("Error reading file.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    // A Range is only honoured if the client's partial copy is of this file.
+    let range_applies = match header(header::IF_RANGE) {
+        Some(if_range) if if_range.trim().starts_with('"') => if_range.trim() == etag,
+        Some(if_range) => not_modified_since(if_range, file.created_at),
+        None => true,
+    };
+    let range = match header(header::RANGE) {
+        Some(range) if range_applies => parse_range(range, size),
+        _ => None,
+    };
+
+    // Images are shown in the browser, anything that could run script there
+    // is always downloaded.
+    let disposition = if thumbnails::is_image(&file.mime) { "inline" } else { "attachment" };
+    let response = response
+        .header(header::CONTENT_TYPE, &file.mime)
+        .header(header::CONTENT_DISPOSITION, content_disposition(disposition, &file.original_name))
+        .header("X-Content-Type-Options", "nosniff")
+        .header(header::CONTENT_SECURITY_POLICY, "sandbox");
+    let (response, start, len) = match range {
+        None => (response.status(StatusCode::OK), 0, size),
+        Some(ByteRange::Partial(start, end)) => (
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+            start,
+            end - start + 1,
+        ),
+        Some(ByteRange::Unsatisfiable) => {
+            return Ok(Box::new(
+                response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::empty()),
+            ))
+        }
+    };
+
+    let content = match STORAGE.get(&key, start, len).await {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read file {}: {}", file.id, e);
+            return Ok(json_message("Error reading file.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+    let body = Body::wrap_stream(content);
+    Ok(Box::new(response.header(header::CONTENT_LENGTH, len).body(body)))
+}
+
+/// `GET /download/{id}/link`: a signed link to the file that can be shared
+/// with clients that have no session, such as a media player.
+pub async fn handle_download_link(
+    file_id: String,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let no_link = DownloadQuery {
+        expires: None,
+        signature: None,
+    };
+    let downloader =
+        match authorize_download(&db, &file_id, &no_link, session_token.as_deref(), authorization.as_deref()).await {
+            Ok(downloader) => downloader,
+            Err(reply) => return Ok(reply),
+        };
+    let file = match find_downloadable(&db, &file_id, &downloader).await {
+        Ok(file) => file,
+        Err(reply) => return Ok(reply),
+    };
+
+    let expires_at = crate::db::now() + CONFIG.download_link_ttl;
+    Ok(Box::new(warp::reply::json(&DownloadLink {
+        url: signed_download_url(&file.id, expires_at),
+        expires_at,
+    })))
+}
+
+/// `GET /thumbnails/{id}`: a thumbnail from a File frame, for anyone who can
+/// see a room the image was shared in. Thumbnails never change, so they may
+/// be cached for good.
+pub async fn handle_thumbnail(
+    thumbnail_id: String,
+    session_token: Option<String>,
+    authorization: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let identity = match auth::authenticate(&db, session_token.as_deref(), authorization.as_deref()).await {
+        Some(identity) => identity,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let id = thumbnail_id.clone();
+    let found: rusqlite::Result<(String, Vec<String>)> = db
+        .run(move |conn| {
+            let mime = conn.query_row(
+                "SELECT thumbnail_mime FROM images WHERE thumbnail_id = ?1",
+                params![id],
+                |row| row.get(0),
+            )?;
+            let mut stmt = conn.prepare(
+                "SELECT DISTINCT files.room FROM files JOIN images ON images.sha256 = files.sha256
+                 WHERE images.thumbnail_id = ?1",
+            )?;
+            let rooms = stmt
+                .query_map(params![id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok((mime, rooms))
+        })
+        .await;
+    let mime = match found {
+        Ok((mime, rooms)) if rooms.iter().any(|room| identity.can_access(room)) => mime,
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Ok(json_message("Thumbnail not found.", StatusCode::NOT_FOUND))
+        }
+        Err(e) => {
+            eprintln!("Failed to look up thumbnail {}: {}", thumbnail_id, e);
+            return Ok(json_message("Error reading file.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    let content = match STORAGE.get(&thumbnail_id, 0, u64::MAX).await {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read thumbnail {}: {}", thumbnail_id, e);
+            return Ok(json_message("Error reading file.", StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, mime)
+        .header(header::CACHE_CONTROL, "private, max-age=31536000, immutable")
+        .header("X-Content-Type-Options", "nosniff")
+        .body(Body::wrap_stream(content));
+    Ok(Box::new(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    #[test]
+    fn sanitize_filename_drops_directories() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\..\\boot.ini"), "boot.ini");
+        assert_eq!(sanitize_filename("/abs/path/report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("dir/"), "file");
+        assert_eq!(sanitize_filename(".."), "file");
+    }
+
+    #[test]
+    fn sanitize_filename_drops_control_characters_and_dots() {
+        assert_eq!(sanitize_filename("a\0b.txt"), "ab.txt");
+        assert_eq!(sanitize_filename("evil\r\nSet-Cookie: x"), "evilSet-Cookie: x");
+        assert_eq!(sanitize_filename(".htaccess"), "htaccess");
+        assert_eq!(sanitize_filename("  . hidden "), "hidden");
+        assert_eq!(sanitize_filename("\0\0"), "file");
+        assert_eq!(sanitize_filename(""), "file");
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_unicode_within_the_length_limit() {
+        assert_eq!(sanitize_filename("résumé 😀.pdf"), "résumé 😀.pdf");
+        // Two bytes per character, so 255 bytes would split one.
+        let long = sanitize_filename(&"é".repeat(200));
+        assert_eq!(long, "é".repeat(127));
+        assert_eq!(sanitize_filename(&"a".repeat(300)).len(), MAX_FILENAME_LEN);
+    }
+
+    #[test]
+    fn content_disposition_encodes_unicode() {
+        assert_eq!(
+            content_disposition("attachment", "résumé.pdf"),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+        assert_eq!(
+            content_disposition("inline", "😀.png"),
+            "inline; filename=\"_.png\"; filename*=UTF-8''%F0%9F%98%80.png"
+        );
+    }
+
+    #[test]
+    fn content_disposition_escapes_hostile_names() {
+        assert_eq!(
+            content_disposition("attachment", "say \"hi\".txt"),
+            "attachment; filename=\"say _hi_.txt\"; filename*=UTF-8''say%20%22hi%22.txt"
+        );
+        assert_eq!(
+            content_disposition("attachment", "../x\0y;\r\nfoo=bar"),
+            "attachment; filename=\"xy;foo=bar\"; filename*=UTF-8''xy%3Bfoo%3Dbar"
+        );
+        for name in ["../../etc/passwd", "a\0b", "q\"uote\\", "line\nbreak", "日本語.txt", "%41.txt"] {
+            let value = content_disposition("attachment", name);
+            assert!(HeaderValue::from_str(&value).is_ok(), "{:?} gave {:?}", name, value);
+            let encoded = value.split("filename*=UTF-8''").nth(1).unwrap();
+            assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~%".contains(&b)));
+        }
+    }
+
+    #[test]
+    fn parse_range_resolves_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(ByteRange::Partial(0, 99)));
+        assert_eq!(parse_range(" bytes=10-10 ", 1000), Some(ByteRange::Partial(10, 10)));
+        // Ends past the file are cut short.
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(ByteRange::Partial(900, 999)));
+        // Open-ended: to the end of the file.
+        assert_eq!(parse_range("bytes=500-", 1000), Some(ByteRange::Partial(500, 999)));
+        // Suffix: the last N bytes, or all of them if the file is shorter.
+        assert_eq!(parse_range("bytes=-100", 1000), Some(ByteRange::Partial(900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(ByteRange::Partial(0, 999)));
+    }
+
+    #[test]
+    fn parse_range_rejects_ranges_past_the_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(ByteRange::Unsatisfiable));
+        // Nothing of an empty file can be asked for.
+        assert_eq!(parse_range("bytes=0-", 0), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-0", 0), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-10", 0), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn parse_range_ignores_what_it_does_not_serve() {
+        // Multiple ranges and malformed ones get the whole file.
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), None);
+        assert_eq!(parse_range("bytes=0-9, -5", 1000), None);
+        assert_eq!(parse_range("bytes=9-0", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+        assert_eq!(parse_range("0-9", 1000), None);
+    }
+
+    #[test]
+    fn etag_matches_weakly() {
+        let etag = "\"abc\"";
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("W/\"abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(etag_matches("\"x\", W/\"abc\" , \"y\"", etag));
+        assert!(etag_matches("\"x\",*", etag));
+        assert!(!etag_matches("\"abcd\"", etag));
+        assert!(!etag_matches("abc", etag));
+        assert!(!etag_matches("\"x\", \"y\"", etag));
+        assert!(!etag_matches("", etag));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let etag = "\"abc\"";
+        let modified = 1_700_000_000;
+        let later = http_date(modified + 60);
+        let earlier = http_date(modified - 60);
+
+        assert!(not_modified(None, Some(&later), etag, modified));
+        assert!(not_modified(None, Some(&http_date(modified)), etag, modified));
+        assert!(!not_modified(None, Some(&earlier), etag, modified));
+        assert!(!not_modified(None, Some("yesterday"), etag, modified));
+        assert!(!not_modified(None, None, etag, modified));
+
+        // With If-None-Match, the date is not looked at.
+        assert!(!not_modified(Some("\"other\""), Some(&later), etag, modified));
+        assert!(not_modified(Some("W/\"abc\""), Some(&earlier), etag, modified));
+    }
+
+    fn link_signature(file_id: &str, expires: i64) -> String {
+        let url = signed_download_url(file_id, expires);
+        url.rsplit_once("signature=").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn signed_links_expire() {
+        let expires = 1_700_000_000;
+        let signature = link_signature("f1", expires);
+        assert_eq!(check_download_link("f1", expires, &signature, expires - 60), Ok(()));
+        assert_eq!(check_download_link("f1", expires, &signature, expires), Ok(()));
+        assert_eq!(
+            check_download_link("f1", expires, &signature, expires + 1),
+            Err("Download link has expired.")
+        );
+    }
+
+    #[test]
+    fn signed_links_cannot_be_altered() {
+        let expires = 1_700_000_000;
+        let signature = link_signature("f1", expires);
+        let now = expires - 60;
+        let invalid = Err("Invalid download link.");
+
+        // Another file, or a later expiry.
+        assert_eq!(check_download_link("f2", expires, &signature, now), invalid);
+        assert_eq!(check_download_link("f1", expires + 3600, &signature, now), invalid);
+
+        let mut flipped = signature.clone().into_bytes();
+        flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+        let flipped = String::from_utf8(flipped).unwrap();
+        for signature in [&flipped, &signature[..32], "", "not hex"] {
+            assert_eq!(check_download_link("f1", expires, signature, now), invalid, "{}", signature);
+        }
+    }
+
+    async fn download_status(db: &Db, file_id: &str, expires: Option<i64>, signature: Option<String>) -> StatusCode {
+        let query = DownloadQuery { expires, signature };
+        let reply = handle_file_download(file_id.into(), query, None, None, HeaderMap::new(), db.clone())
+            .await
+            .unwrap();
+        warp::Reply::into_response(reply).status()
+    }
+
+    #[tokio::test]
+    async fn downloads_are_authorized_before_the_file_is_looked_up() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let expires = crate::db::now() + 60;
+
+        // Without credentials, unknown ids look the same as any other.
+        assert_eq!(download_status(&db, "missing", None, None).await, StatusCode::UNAUTHORIZED);
+        let reply = handle_download_link("missing".into(), None, None, db.clone()).await.unwrap();
+        assert_eq!(warp::Reply::into_response(reply).status(), StatusCode::UNAUTHORIZED);
+        let forged = link_signature("other", expires);
+        assert_eq!(
+            download_status(&db, "missing", Some(expires), Some(forged)).await,
+            StatusCode::FORBIDDEN
+        );
+
+        // Only a valid link gets as far as the lookup.
+        let signature = link_signature("missing", expires);
+        assert_eq!(
+            download_status(&db, "missing", Some(expires), Some(signature)).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    async fn add_blob(db: &Db, sha256: &'static str, size: u64, refcount: i64, thumbnail: Option<&'static str>) {
+        db.run(move |conn| {
+            conn.execute(
+                "INSERT INTO blobs (sha256, storage_key, size, refcount, created_at) VALUES (?1, ?2, ?3, ?4, 0)",
+                params![sha256, Uuid::new_v4().to_string(), size, refcount],
+            )?;
+            if thumbnail.is_some() {
+                conn.execute(
+                    "INSERT INTO images (sha256, width, height, thumbnail_id, thumbnail_mime) VALUES (?1, 1, 1, ?2, 'image/png')",
+                    params![sha256, thumbnail],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    /// The blob's refcount, `None` once it is gone.
+    async fn refcount(db: &Db, sha256: &'static str) -> Option<i64> {
+        db.run(move |conn| {
+            conn.query_row("SELECT refcount FROM blobs WHERE sha256 = ?1", params![sha256], |row| row.get(0))
+                .optional()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn put_blob_references_stored_content() {
+        let (dir, db) = crate::db::open_temp().await;
+        add_blob(&db, "abc", 5, 1, None).await;
+
+        // The same content again is not stored a second time.
+        let upload = dir.path().join("upload.part");
+        fs::write(&upload, b"hello").await.unwrap();
+        put_blob(&db, "abc", 5, "text/plain", &upload).await.unwrap();
+        assert_eq!(refcount(&db, "abc").await, Some(2));
+        assert!(!upload.exists());
+
+        // A hash with another size is not the same content.
+        let referenced = db.run(|conn| reference_blob(conn, "abc", 6)).await.unwrap();
+        assert!(!referenced);
+        assert_eq!(refcount(&db, "abc").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn release_blob_deletes_with_the_last_reference() {
+        let (_dir, db) = crate::db::open_temp().await;
+        add_blob(&db, "abc", 5, 2, Some("thumb")).await;
+        add_blob(&db, "def", 5, 1, None).await;
+
+        release_blob(&db, "abc").await;
+        assert_eq!(refcount(&db, "abc").await, Some(1));
+        release_blob(&db, "abc").await;
+        assert_eq!(refcount(&db, "abc").await, None);
+        let images: i64 = db
+            .run(|conn| conn.query_row("SELECT COUNT(*) FROM images", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(images, 0);
+
+        // Other blobs are left alone, and releasing a gone blob does nothing.
+        release_blob(&db, "abc").await;
+        assert_eq!(refcount(&db, "def").await, Some(1));
+    }
+}
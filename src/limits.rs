@@ -0,0 +1,327 @@
+use std::fmt;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use warp::http::StatusCode;
+
+use crate::config::CONFIG;
+use crate::db::Db;
+use crate::protocol::ErrorCode;
+
+/// How much of a file is looked at to work out its type.
+pub const SNIFF_LEN: usize = 8192;
+
+/// Why an upload was refused.
+#[derive(Debug)]
+pub enum Rejection {
+    TooLarge { max: u64 },
+    UserQuota { used: u64, quota: u64 },
+    GlobalQuota,
+    TypeNotAllowed(String),
+    /// Metadata was to be removed, but the image is not well-formed.
+    UnreadableImage(String),
+    /// The malware scanner could not be reached or failed.
+    ScanFailed,
+    /// Storage usage could not be read, so the quotas could not be checked.
+    QuotaUnavailable,
+}
+
+impl Rejection {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Rejection::TooLarge { .. } => ErrorCode::FileTooLarge,
+            Rejection::UserQuota { .. } | Rejection::GlobalQuota => ErrorCode::QuotaExceeded,
+            Rejection::TypeNotAllowed(_) => ErrorCode::FileTypeNotAllowed,
+            Rejection::UnreadableImage(_) => ErrorCode::InvalidRequest,
+            Rejection::ScanFailed | Rejection::QuotaUnavailable => ErrorCode::Internal,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Rejection::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Rejection::UserQuota { .. } | Rejection::GlobalQuota => StatusCode::INSUFFICIENT_STORAGE,
+            Rejection::TypeNotAllowed(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Rejection::UnreadableImage(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Rejection::ScanFailed | Rejection::QuotaUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::TooLarge { max } => write!(f, "File is larger than the {} byte limit.", max),
+            Rejection::UserQuota { used, quota } => write!(
+                f,
+                "Upload would exceed your storage quota ({} of {} bytes used).",
+                used, quota
+            ),
+            Rejection::GlobalQuota => write!(f, "The server is out of upload space."),
+            Rejection::TypeNotAllowed(mime) => write!(f, "Files of type {} are not allowed.", mime),
+            Rejection::UnreadableImage(reason) => {
+                write!(f, "Could not remove the image's metadata: {}.", reason)
+            }
+            Rejection::ScanFailed => write!(f, "The file could not be checked for malware, try again later."),
+            Rejection::QuotaUnavailable => write!(f, "Storage quotas could not be checked, try again later."),
+        }
+    }
+}
+
+pub fn check_file_size(size: u64) -> Result<(), Rejection> {
+    if size > CONFIG.max_file_size {
+        return Err(Rejection::TooLarge {
+            max: CONFIG.max_file_size,
+        });
+    }
+    Ok(())
+}
+
+/// `pending` is what `uploader` has reserved for uploads still in progress,
+/// which count as if they were stored already.
+fn check_quota(conn: &Connection, uploader: &str, size: u64, pending: u64) -> rusqlite::Result<Result<(), Rejection>> {
+    if let Err(rejection) = check_file_size(size) {
+        return Ok(Err(rejection));
+    }
+
+    let stored: u64 = conn
+        .query_row(
+            "SELECT bytes FROM storage_usage WHERE username = ?1",
+            params![uploader],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let used = stored + pending;
+    if used + size > CONFIG.user_quota {
+        return Ok(Err(Rejection::UserQuota {
+            used,
+            quota: CONFIG.user_quota,
+        }));
+    }
+    let total: u64 = conn.query_row("SELECT COALESCE(SUM(bytes), 0) FROM storage_usage", [], |row| row.get(0))?;
+    if total + pending + size > CONFIG.global_quota {
+        return Ok(Err(Rejection::GlobalQuota));
+    }
+    Ok(Ok(()))
+}
+
+/// Checks that `uploader` may store another `size` bytes on top of the
+/// `pending` bytes of their unfinished uploads, without charging them. Used
+/// to turn uploads away before receiving them.
+pub async fn check_size(db: &Db, uploader: &str, size: u64, pending: u64) -> Result<(), Rejection> {
+    let uploader = uploader.to_string();
+    db.run(move |conn| check_quota(conn, &uploader, size, pending))
+        .await
+        .unwrap_or_else(|e| {
+            // Letting the upload through could overrun the quotas; the
+            // client may try again.
+            eprintln!("Failed to check storage quotas: {}", e);
+            Err(Rejection::QuotaUnavailable)
+        })
+}
+
+/// Checks the quotas and adds `size` bytes to `uploader`'s usage.
+pub async fn charge(db: &Db, uploader: &str, size: u64) -> Result<(), Rejection> {
+    let name = uploader.to_string();
+    let result = db
+        .run(move |conn| {
+            // Immediate, so two uploads cannot both fit in the last of a quota.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let checked = check_quota(&tx, &name, size, 0)?;
+            if checked.is_ok() {
+                tx.execute(
+                    "INSERT INTO storage_usage (username, bytes) VALUES (?1, ?2)
+                     ON CONFLICT (username) DO UPDATE SET bytes = bytes + excluded.bytes",
+                    params![name, size],
+                )?;
+                tx.commit()?;
+            }
+            Ok(checked)
+        })
+        .await;
+    result.unwrap_or_else(|e| {
+        eprintln!("Failed to record storage used by {}: {}", uploader, e);
+        Err(Rejection::QuotaUnavailable)
+    })
+}
+
+/// Gives back bytes charged for a file that was not kept.
+pub async fn refund(db: &Db, uploader: &str, size: u64) {
+    let name = uploader.to_string();
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "UPDATE storage_usage SET bytes = MAX(bytes - ?2, 0) WHERE username = ?1",
+                params![name, size],
+            )
+        })
+        .await;
+    if let Err(e) = result {
+        eprintln!("Failed to update storage used by {}: {}", uploader, e);
+    }
+}
+
+/// Works out a file's MIME type from its first bytes, ignoring whatever
+/// name or type the client claimed.
+pub fn sniff(head: &[u8]) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+    // A cut off multi-byte character at the end still counts as text.
+    let text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if text && !head.contains(&0) {
+        "text/plain".to_string()
+    } else {
+        "application/octet-stream".to_string()
+    }
+}
+
+fn matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime.split('/').next() == Some(prefix),
+        None => pattern == "*" || pattern == mime,
+    }
+}
+
+/// Sniffs the type from the start of a file and checks it against the
+/// allow and deny lists. Returns the sniffed type.
+pub fn check_type(head: &[u8]) -> Result<String, Rejection> {
+    let mime = sniff(&head[..head.len().min(SNIFF_LEN)]);
+    check_mime(&mime)?;
+    Ok(mime)
+}
+
+/// Checks an already sniffed type against the allow and deny lists.
+pub fn check_mime(mime: &str) -> Result<(), Rejection> {
+    if !type_allowed(mime, &CONFIG.mime_allow, &CONFIG.mime_deny) {
+        return Err(Rejection::TypeNotAllowed(mime.to_string()));
+    }
+    Ok(())
+}
+
+/// Denied types are refused even if allowed; an empty allow list allows
+/// everything else.
+fn type_allowed(mime: &str, allow: &[String], deny: &[String]) -> bool {
+    let denied = deny.iter().any(|pattern| matches(pattern, mime));
+    let allowed = allow.is_empty() || allow.iter().any(|pattern| matches(pattern, mime));
+    allowed && !denied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn set_usage(db: &Db, username: &'static str, bytes: u64) {
+        db.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO storage_usage (username, bytes) VALUES (?1, ?2)",
+                params![username, bytes],
+            )
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn usage(db: &Db, username: &'static str) -> u64 {
+        db.run(move |conn| {
+            conn.query_row("SELECT bytes FROM storage_usage WHERE username = ?1", params![username], |row| {
+                row.get(0)
+            })
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_the_user_quota() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let quota = CONFIG.user_quota;
+        assert!(check_size(&db, "alice", 1, 0).await.is_ok());
+
+        set_usage(&db, "alice", quota - 100).await;
+        assert!(check_size(&db, "alice", 100, 0).await.is_ok());
+        assert!(matches!(
+            check_size(&db, "alice", 101, 0).await,
+            Err(Rejection::UserQuota { used, .. }) if used == quota - 100
+        ));
+        // Unfinished uploads count against it too.
+        assert!(matches!(
+            check_size(&db, "alice", 50, 60).await,
+            Err(Rejection::UserQuota { used, .. }) if used == quota - 40
+        ));
+        assert!(check_size(&db, "bob", 101, 0).await.is_ok());
+
+        assert!(matches!(
+            check_size(&db, "bob", CONFIG.max_file_size + 1, 0).await,
+            Err(Rejection::TooLarge { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn checks_the_global_quota() {
+        let (_dir, db) = crate::db::open_temp().await;
+        set_usage(&db, "alice", CONFIG.global_quota - 100).await;
+        assert!(check_size(&db, "bob", 100, 0).await.is_ok());
+        assert!(matches!(check_size(&db, "bob", 101, 0).await, Err(Rejection::GlobalQuota)));
+        assert!(matches!(check_size(&db, "bob", 1, 100).await, Err(Rejection::GlobalQuota)));
+    }
+
+    #[tokio::test]
+    async fn charges_only_what_fits() {
+        let (_dir, db) = crate::db::open_temp().await;
+        charge(&db, "alice", 1000).await.unwrap();
+        charge(&db, "alice", 500).await.unwrap();
+        assert_eq!(usage(&db, "alice").await, 1500);
+
+        set_usage(&db, "bob", CONFIG.user_quota).await;
+        assert!(matches!(charge(&db, "bob", 1).await, Err(Rejection::UserQuota { .. })));
+        assert_eq!(usage(&db, "bob").await, CONFIG.user_quota);
+
+        refund(&db, "alice", 2000).await;
+        assert_eq!(usage(&db, "alice").await, 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_uploads_when_usage_cannot_be_read() {
+        let (_dir, db) = crate::db::open_temp().await;
+        db.run(|conn| conn.execute("DROP TABLE storage_usage", [])).await.unwrap();
+
+        let rejection = check_size(&db, "alice", 1, 0).await.unwrap_err();
+        assert!(matches!(rejection, Rejection::QuotaUnavailable));
+        assert_eq!(rejection.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(matches!(charge(&db, "alice", 1).await, Err(Rejection::QuotaUnavailable)));
+    }
+
+    #[test]
+    fn matches_exact_types_and_wildcards() {
+        assert!(matches("image/png", "image/png"));
+        assert!(!matches("image/png", "image/jpeg"));
+        assert!(matches("image/*", "image/png"));
+        assert!(!matches("image/*", "imagery/png"));
+        assert!(!matches("image/*", "text/plain"));
+        assert!(matches("*", "application/octet-stream"));
+        assert!(!matches("image", "image/png"));
+    }
+
+    #[test]
+    fn deny_list_wins_over_allow_list() {
+        let list = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert!(type_allowed("text/plain", &[], &[]));
+        assert!(type_allowed("image/png", &list(&["image/*"]), &[]));
+        assert!(!type_allowed("text/plain", &list(&["image/*"]), &[]));
+        assert!(!type_allowed("image/svg+xml", &list(&["image/*"]), &list(&["image/svg+xml"])));
+        assert!(!type_allowed("image/png", &[], &list(&["*"])));
+    }
+
+    #[test]
+    fn denies_executables_by_default() {
+        assert!(check_mime("text/plain").is_ok());
+        assert!(check_mime("image/png").is_ok());
+        let rejection = check_mime("application/x-executable").unwrap_err();
+        assert!(matches!(&rejection, Rejection::TypeNotAllowed(mime) if mime == "application/x-executable"));
+        assert_eq!(rejection.code(), ErrorCode::FileTypeNotAllowed);
+        assert!(check_type(b"MZ\x90\0\x03\0\0\0").is_err());
+    }
+}
@@ -1,13 +1,30 @@
+mod api;
 mod auth;
+mod bots;
 mod chat;
+mod compression;
+mod config;
+mod db;
 mod handling_files;
+mod limits;
+mod metadata;
+mod migrations;
+mod offline;
+mod protocol;
+mod retention;
+mod scanning;
 mod shared;
+mod storage;
+mod thumbnails;
+mod transports;
+mod uploads;
+mod webhooks;
+mod websocket;
 
 use warp::Filter;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
-use env_logger;
-use rusqlite::Connection;
+use uuid::Uuid;
 
 use crate::chat::Clients;
 
@@ -15,48 +32,215 @@ use crate::chat::Clients;
 async fn main() {
     env_logger::init();
 
-    let conn = Connection::open("users.db").expect("Failed to open the database");
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username TEXT NOT NULL UNIQUE,
-            password TEXT NOT NULL
-        )",
-        [],
-    )
-        .expect("Failed to create users table");
+    // `migrate [--dry-run]` only updates the schema; without arguments the
+    // server starts, migrating first.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let migrate_only = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => None,
+        ["migrate"] => Some(false),
+        ["migrate", "--dry-run"] => Some(true),
+        _ => {
+            eprintln!("Usage: websockets2 [migrate [--dry-run]]");
+            std::process::exit(2);
+        }
+    };
 
-    let db = Arc::new(Mutex::new(conn));
+    let db = db::Db::open(&config::CONFIG.database_path, config::CONFIG.database_pool_size)
+        .expect("Failed to open the database");
+    if let Some(dry_run) = migrate_only {
+        if let Err(e) = migrations::command(&db, dry_run).await {
+            eprintln!("Failed to migrate the database: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    match migrations::run(&db, false).await {
+        Ok(applied) => {
+            for migration in applied {
+                println!("Applied migration {} ({})", migration.version, migration.name);
+            }
+        }
+        Err(e) => panic!("Failed to migrate the database: {}", e),
+    }
+
+    println!("Storing uploads in {}", storage::STORAGE.describe());
+    if let Some(scanner) = scanning::SCANNER.as_ref() {
+        println!("Scanning uploads with {}", scanner.describe());
+    }
+    let indexed = handling_files::index_existing_files(&db)
+        .await
+        .expect("Failed to index existing uploads");
+    if indexed > 0 {
+        println!("Indexed {} existing uploads", indexed);
+    }
 
     let register_db = db.clone();
     let login_db = db.clone();
+    let chat_db = db.clone();
+    let webhooks_db = db.clone();
+    let bots_db = db.clone();
+    let api_db = db.clone();
+    let transports_db = db.clone();
+    let upload_db = db.clone();
+    let download_db = db.clone();
+    let thumbnail_db = db.clone();
 
     // Shared state to hold connected clients
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let api_clients = clients.clone();
+    transports::spawn_poll_janitor(clients.clone(), db.clone());
+    uploads::spawn_upload_janitor();
+    retention::spawn_file_janitor(clients.clone(), db.clone());
     let clients_filter = warp::any().map(move || clients.clone());
 
     let register_route = warp::path("register")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(api::json_body())
         .and(auth::with_db(register_db))
         .and_then(auth::handle_register);
 
     let login_route = warp::path("login")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(api::json_body())
         .and(auth::with_db(login_db))
         .and_then(auth::handle_login);
 
     let chat_route = warp::path("ws")
-        .and(warp::ws())
+        .and(websocket::ws())
+        .and(warp::query::<chat::WsQuery>())
         .and(warp::cookie::optional("session_token"))
-        .and(clients_filter)
+        .and(clients_filter.clone())
+        .and(auth::with_db(chat_db))
         .and_then(chat::handle_ws_auth);
 
-    let download_file_route = warp::path("download")
-        .and(warp::path::param::<String>()) // File ID
+    let sse_route = warp::path!("events")
+        .and(warp::get())
+        .and(warp::query::<transports::VersionQuery>())
+        .and(warp::cookie::optional("session_token"))
+        .and(clients_filter.clone())
+        .and(auth::with_db(transports_db.clone()))
+        .and_then(transports::handle_sse);
+
+    let send_route = warp::path!("send")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(websocket::MAX_MESSAGE_SIZE as u64))
+        .and(warp::body::bytes())
+        .and(warp::cookie::optional("session_token"))
+        .and(clients_filter.clone())
+        .and(auth::with_db(transports_db.clone()))
+        .and_then(transports::handle_send);
+
+    let poll_connect_route = warp::path!("poll")
+        .and(warp::post())
+        .and(warp::query::<transports::VersionQuery>())
+        .and(warp::cookie::optional("session_token"))
+        .and(clients_filter.clone())
+        .and(auth::with_db(transports_db.clone()))
+        .and_then(transports::handle_poll_connect);
+
+    let poll_route = warp::path!("poll" / Uuid)
+        .and(warp::get())
+        .and(warp::cookie::optional("session_token"))
+        .and_then(transports::handle_poll);
+
+    let poll_disconnect_route = warp::path!("poll" / Uuid)
+        .and(warp::delete())
+        .and(warp::cookie::optional("session_token"))
+        .and(clients_filter.clone())
+        .and(auth::with_db(transports_db))
+        .and_then(transports::handle_poll_disconnect);
+
+    let create_webhook_route = warp::path!("webhooks")
+        .and(warp::post())
+        .and(api::json_body())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(webhooks_db.clone()))
+        .and_then(webhooks::handle_create_webhook);
+
+    let list_webhooks_route = warp::path!("webhooks")
+        .and(warp::get())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(webhooks_db.clone()))
+        .and_then(webhooks::handle_list_webhooks);
+
+    let delete_webhook_route = warp::path!("webhooks" / i64)
+        .and(warp::delete())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(webhooks_db.clone()))
+        .and_then(webhooks::handle_delete_webhook);
+
+    let webhook_deliveries_route = warp::path!("webhooks" / i64 / "deliveries")
+        .and(warp::get())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(webhooks_db))
+        .and_then(webhooks::handle_list_deliveries);
+
+    let create_bot_route = warp::path!("bots")
+        .and(warp::post())
+        .and(api::json_body())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(bots_db.clone()))
+        .and_then(bots::handle_create_bot);
+
+    let create_token_route = warp::path!("bots" / i64 / "tokens")
+        .and(warp::post())
+        .and(api::json_body())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(bots_db.clone()))
+        .and_then(bots::handle_create_token);
+
+    let revoke_token_route = warp::path!("bots" / i64 / "tokens" / i64)
+        .and(warp::delete())
+        .and(warp::cookie::optional("session_token"))
+        .and(auth::with_db(bots_db.clone()))
+        .and_then(bots::handle_revoke_token);
+
+    // Kept for bots written against the unversioned endpoint.
+    let post_message_route = warp::path!("api" / "rooms" / String / "messages")
+        .and(warp::post())
+        .and(api::json_body())
+        .and(warp::cookie::optional("session_token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth::with_db(bots_db))
+        .and(clients_filter.clone())
+        .and_then(api::handle_post_message);
+
+    let api_routes = api::routes(api_clients, api_db);
+
+    // Streamed to disk, so there is no need for warp's in-memory size cap.
+    let upload_route = warp::path("upload")
+        .and(warp::post())
+        .and(warp::query::<handling_files::UploadQuery>())
+        .and(warp::multipart::form().max_length(None))
+        .and(warp::cookie::optional("session_token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(clients_filter.clone())
+        .and(auth::with_db(upload_db))
+        .and_then(handling_files::handle_upload);
+
+    let download_file_route = warp::path!("download" / String)
+        .and(warp::get())
+        .and(warp::query::<handling_files::DownloadQuery>())
+        .and(warp::cookie::optional("session_token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::headers_cloned())
+        .and(auth::with_db(download_db.clone()))
         .and_then(handling_files::handle_file_download);
 
+    let download_link_route = warp::path!("download" / String / "link")
+        .and(warp::get())
+        .and(warp::cookie::optional("session_token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth::with_db(download_db))
+        .and_then(handling_files::handle_download_link);
+
+    let thumbnail_route = warp::path!("thumbnails" / String)
+        .and(warp::get())
+        .and(warp::cookie::optional("session_token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth::with_db(thumbnail_db))
+        .and_then(handling_files::handle_thumbnail);
+
     let root_route = warp::path::end()
         .map(|| warp::redirect::temporary(warp::http::Uri::from_static("/login.html")));
 
@@ -64,18 +248,38 @@ async fn main() {
 
     let cors = warp::cors()
         .allow_any_origin() // For development; specify allowed origins in production
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["Content-Type"]);
+        .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
+        .allow_headers(vec!["Content-Type", "Authorization"])
+        // Totals of the file listing.
+        .expose_headers(vec!["X-Total-Count", "X-Total-Size"]);
 
     let routes = root_route
         .or(login_route)
         .or(register_route)
         .or(chat_route)
+        .or(sse_route)
+        .or(send_route)
+        .or(poll_connect_route)
+        .or(poll_route)
+        .or(poll_disconnect_route)
+        .or(upload_route)
         .or(download_file_route)
+        .or(download_link_route)
+        .or(thumbnail_route)
+        .or(create_webhook_route)
+        .or(list_webhooks_route)
+        .or(delete_webhook_route)
+        .or(webhook_deliveries_route)
+        .or(create_bot_route)
+        .or(create_token_route)
+        .or(revoke_token_route)
+        .or(post_message_route)
+        .or(api_routes)
         .or(static_files)
         .with(cors)
         .with(warp::log("chat_app"));
 
-    println!("Server running at http://localhost:3030/");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    let (address, server) = websocket::serve(warp::service(routes), config::CONFIG.address);
+    println!("Server running at http://{}/", address);
+    server.await;
 }
@@ -0,0 +1,410 @@
+use crate::config::CONFIG;
+
+/// Sniffed types whose metadata can be removed.
+const STRIPPABLE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+/// Whether metadata is removed from an upload of type `mime`. `requested`
+/// is the uploader's choice, if they made one; otherwise the server's
+/// `CHAT_STRIP_METADATA` setting decides.
+pub fn applies(mime: &str, requested: Option<bool>) -> bool {
+    STRIPPABLE_TYPES.contains(&mime) && requested.unwrap_or(CONFIG.strip_metadata)
+}
+
+/// Removes EXIF, XMP, IPTC, comments and text chunks from an image of type
+/// `mime` without re-encoding it. A JPEG's orientation is kept, since the
+/// image would otherwise show up rotated. Fails if the file does not have
+/// the structure its type calls for.
+pub fn strip(mime: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    match mime {
+        "image/jpeg" => strip_jpeg(data),
+        "image/png" => strip_png(data),
+        "image/webp" => strip_webp(data),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP1: u8 = 0xE1;
+/// Photoshop resources, where IPTC data lives.
+const JPEG_APP13: u8 = 0xED;
+const JPEG_COM: u8 = 0xFE;
+const JPEG_SOS: u8 = 0xDA;
+const JPEG_EOI: u8 = 0xD9;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+fn strip_jpeg(data: &[u8]) -> Result<Vec<u8>, String> {
+    if !data.starts_with(&JPEG_SOI) {
+        return Err("missing JPEG start marker".into());
+    }
+
+    let mut kept = Vec::with_capacity(data.len());
+    let mut orientation = None;
+    let mut pos = 2;
+    loop {
+        match data.get(pos..pos + 2) {
+            Some([0xFF, 0xFF]) => {
+                // Fill byte before a marker.
+                pos += 1;
+                continue;
+            }
+            Some([0xFF, marker]) => {
+                let marker = *marker;
+                // Markers without a length.
+                if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+                    kept.extend_from_slice(&data[pos..pos + 2]);
+                    pos += 2;
+                    continue;
+                }
+                // Everything from the first scan on is image data.
+                if marker == JPEG_EOI || marker == JPEG_SOS {
+                    kept.extend_from_slice(&data[pos..]);
+                    break;
+                }
+
+                let len = data
+                    .get(pos + 2..pos + 4)
+                    .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+                    .filter(|len| *len >= 2 && pos + 2 + len <= data.len())
+                    .ok_or("truncated JPEG segment")?;
+                let segment = &data[pos..pos + 2 + len];
+                match marker {
+                    JPEG_APP1 => {
+                        if let Some(exif) = segment[4..].strip_prefix(EXIF_HEADER) {
+                            orientation = orientation.or_else(|| exif_orientation(exif));
+                        }
+                    }
+                    JPEG_APP13 | JPEG_COM => {}
+                    _ => kept.extend_from_slice(segment),
+                }
+                pos += 2 + len;
+            }
+            _ => return Err("malformed JPEG marker".into()),
+        }
+    }
+
+    let mut stripped = JPEG_SOI.to_vec();
+    if let Some(orientation) = orientation.filter(|orientation| *orientation != 1) {
+        stripped.extend_from_slice(&orientation_segment(orientation));
+    }
+    stripped.extend_from_slice(&kept);
+    Ok(stripped)
+}
+
+/// The orientation tag from the first IFD of an EXIF block.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let u32_at = |pos: usize| {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?, *tiff.get(pos + 2)?, *tiff.get(pos + 3)?];
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|entry| u16_at(*entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// An APP1 segment holding nothing but an orientation tag.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    // Tag 0x0112, type SHORT, one value, padded to four bytes.
+    tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    // No further IFDs.
+    tiff.extend_from_slice(&[0, 0, 0, 0]);
+
+    let len = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+    let mut segment = vec![0xFF, JPEG_APP1];
+    segment.extend_from_slice(&len.to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// EXIF, free text (XMP is an `iTXt` chunk) and the modification time.
+const PNG_METADATA_CHUNKS: &[&[u8; 4]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+fn strip_png(data: &[u8]) -> Result<Vec<u8>, String> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return Err("missing PNG signature".into());
+    }
+
+    let mut stripped = PNG_SIGNATURE.to_vec();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8).ok_or("truncated PNG chunk")?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind: &[u8; 4] = header[4..8].try_into().expect("four bytes");
+        // Length, type, data and CRC.
+        let end = pos + 12 + len;
+        let chunk = data.get(pos..end).ok_or("truncated PNG chunk")?;
+        if !PNG_METADATA_CHUNKS.contains(&kind) {
+            stripped.extend_from_slice(chunk);
+        }
+        pos = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(stripped)
+}
+
+/// Flags in the first byte of a `VP8X` chunk.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+fn strip_webp(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("missing WebP header".into());
+    }
+
+    let mut stripped = data[..12].to_vec();
+    let mut pos = 12;
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8).ok_or("truncated WebP chunk")?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if pos + 8 + len > data.len() {
+            return Err("truncated WebP chunk".into());
+        }
+        // Chunks are padded to an even length.
+        let end = (pos + 8 + len + (len & 1)).min(data.len());
+        match &header[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = stripped.len();
+                stripped.extend_from_slice(&data[pos..end]);
+                if let Some(flags) = stripped.get_mut(start + 8) {
+                    *flags &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+                }
+            }
+            _ => stripped.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+
+    let riff_len = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Ok(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SECRET: &[u8] = b"48.8584N 2.2945E";
+
+    fn encode(format: image::ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        image::RgbImage::from_fn(32, 24, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 128]))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    fn decodes(data: &[u8]) -> bool {
+        image::load_from_memory(data).is_ok_and(|image| (image.width(), image.height()) == (32, 24))
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    /// A little-endian EXIF block with an orientation and a GPS IFD that
+    /// points at `SECRET`.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        // Orientation, SHORT.
+        tiff.extend_from_slice(&[0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0]);
+        // GPS IFD pointer, LONG, right after this IFD.
+        tiff.extend_from_slice(&[0x25, 0x88, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        tiff.extend_from_slice(&38u32.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        // GPSLatitude as ASCII, stored after the GPS IFD.
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&[0x02, 0x00, 0x02, 0x00]);
+        tiff.extend_from_slice(&(SECRET.len() as u32).to_le_bytes());
+        tiff.extend_from_slice(&56u32.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(SECRET);
+        tiff
+    }
+
+    fn xmp() -> Vec<u8> {
+        let mut xmp = b"<x:xmpmeta><exif:GPSLatitude>".to_vec();
+        xmp.extend_from_slice(SECRET);
+        xmp.extend_from_slice(b"</exif:GPSLatitude></x:xmpmeta>");
+        xmp
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn jpeg_with_metadata(orientation: u16) -> Vec<u8> {
+        let image = encode(image::ImageFormat::Jpeg);
+        let mut data = JPEG_SOI.to_vec();
+        data.extend(jpeg_segment(JPEG_APP1, &[EXIF_HEADER, &exif(orientation)].concat()));
+        data.extend(jpeg_segment(JPEG_APP1, &[&b"http://ns.adobe.com/xap/1.0/\0"[..], &xmp()].concat()));
+        data.extend(jpeg_segment(JPEG_APP13, &[&b"Photoshop 3.0\0"[..], SECRET].concat()));
+        data.extend(jpeg_segment(JPEG_COM, SECRET));
+        data.extend_from_slice(&image[2..]);
+        data
+    }
+
+    /// The orientation in a JPEG's EXIF block, if it has one.
+    fn jpeg_orientation(data: &[u8]) -> Option<u16> {
+        let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+        (data[2..4] == [0xFF, JPEG_APP1])
+            .then(|| exif_orientation(data[6..4 + len].strip_prefix(EXIF_HEADER)?))
+            .flatten()
+    }
+
+    #[test]
+    fn strips_jpeg_and_keeps_orientation() {
+        let data = jpeg_with_metadata(6);
+        assert!(decodes(&data));
+        assert_eq!(jpeg_orientation(&data), Some(6));
+
+        let stripped = strip("image/jpeg", &data).unwrap();
+        assert!(decodes(&stripped));
+        assert!(!contains(&stripped, SECRET));
+        assert!(!contains(&stripped, b"http://ns.adobe.com/xap/1.0/"));
+        assert_eq!(jpeg_orientation(&stripped), Some(6));
+        // Only the orientation is left of the metadata.
+        assert_eq!(stripped.len(), encode(image::ImageFormat::Jpeg).len() + orientation_segment(6).len());
+    }
+
+    #[test]
+    fn strips_upright_jpeg_orientation_entirely() {
+        let stripped = strip("image/jpeg", &jpeg_with_metadata(1)).unwrap();
+        assert_eq!(stripped, encode(image::ImageFormat::Jpeg));
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    fn png_chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = (payload.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(payload);
+        chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn strips_png() {
+        let image = encode(image::ImageFormat::Png);
+        // After the signature and IHDR.
+        let (head, rest) = image.split_at(PNG_SIGNATURE.len() + 25);
+        let mut data = head.to_vec();
+        data.extend(png_chunk(b"eXIf", &exif(6)));
+        data.extend(png_chunk(b"iTXt", &[&b"XML:com.adobe.xmp\0\0\0\0\0"[..], &xmp()].concat()));
+        data.extend(png_chunk(b"tEXt", &[&b"Comment\0"[..], SECRET].concat()));
+        data.extend(png_chunk(b"zTXt", b"Raw profile\0\0x"));
+        data.extend(png_chunk(b"tIME", &[0x07, 0xE9, 1, 2, 3, 4, 5]));
+        data.extend_from_slice(rest);
+        assert!(decodes(&data));
+
+        let stripped = strip("image/png", &data).unwrap();
+        assert!(decodes(&stripped));
+        assert_eq!(stripped, image);
+    }
+
+    fn webp_chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn strips_webp_and_fixes_up_the_header() {
+        let image = encode(image::ImageFormat::WebP);
+        // The encoder writes a simple file with a single VP8L chunk.
+        let bitstream = image[12..].to_vec();
+        let mut vp8x = vec![WEBP_EXIF_FLAG | WEBP_XMP_FLAG, 0, 0, 0];
+        vp8x.extend_from_slice(&31u32.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&23u32.to_le_bytes()[..3]);
+        // An odd-sized EXIF chunk, so it is padded.
+        let exif = [exif(6), vec![0]].concat();
+        let data = riff(&[
+            webp_chunk(b"VP8X", &vp8x),
+            bitstream.clone(),
+            webp_chunk(b"EXIF", &exif),
+            webp_chunk(b"XMP ", &xmp()),
+        ]);
+        assert!(decodes(&data));
+
+        let stripped = strip("image/webp", &data).unwrap();
+        assert!(decodes(&stripped));
+        assert!(!contains(&stripped, SECRET));
+        assert_eq!(stripped[20] & (WEBP_EXIF_FLAG | WEBP_XMP_FLAG), 0);
+        assert_eq!(u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize, stripped.len() - 8);
+        assert_eq!(stripped, riff(&[webp_chunk(b"VP8X", &[&[0, 0, 0, 0][..], &vp8x[4..]].concat()), bitstream]));
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        let jpeg = jpeg_with_metadata(6);
+        let png = strip("image/png", &encode(image::ImageFormat::Png)).unwrap();
+        let webp = encode(image::ImageFormat::WebP);
+        for (mime, data) in [("image/jpeg", &jpeg), ("image/png", &png), ("image/webp", &webp)] {
+            // Cut inside the first segment after the header.
+            assert!(strip(mime, &data[..20]).is_err(), "{} cut at 20 bytes", mime);
+            assert!(strip(mime, &data[..3]).is_err(), "{} cut at 3 bytes", mime);
+            assert!(strip(mime, b"GIF89a").is_err(), "{} with the wrong signature", mime);
+        }
+        // A PNG has to be cut mid-chunk, not between chunks.
+        assert!(strip("image/png", &png[..png.len() - 6]).is_err());
+        assert!(strip("image/webp", &webp[..webp.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn applies_to_strippable_types_only() {
+        assert!(applies("image/jpeg", Some(true)));
+        assert!(!applies("image/jpeg", Some(false)));
+        assert!(!applies("image/gif", Some(true)));
+        assert!(!applies("text/plain", Some(true)));
+    }
+}
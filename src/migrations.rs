@@ -0,0 +1,416 @@
+use rusqlite::{params, Connection, TransactionBehavior};
+
+use crate::db::Db;
+
+/// One step of the schema. Migrations are applied in order of `version`
+/// and never change once released; a schema change is a new migration at
+/// the end of `MIGRATIONS`.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    up: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        up: |conn| conn.execute_batch(BASELINE),
+    },
+    Migration {
+        version: 2,
+        name: "file_deletion",
+        up: |conn| {
+            // Set on a 'file' message once its file has been deleted;
+            // `deleted_by` stays NULL when the retention policy removed it.
+            add_column(conn, "messages", "deleted_at", "INTEGER")?;
+            add_column(conn, "messages", "deleted_by", "TEXT")
+        },
+    },
+];
+
+/// The schema as it was before migrations were tracked. Every statement
+/// tolerates existing tables, so databases created by those versions are
+/// simply brought up to date.
+const BASELINE: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL
+);
+
+-- `room` is NULL for global webhooks, `events` is a comma separated list of
+-- event names (empty means every event).
+CREATE TABLE IF NOT EXISTS webhooks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    room TEXT,
+    events TEXT NOT NULL DEFAULT '',
+    created_by TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks(id),
+    delivery_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    status_code INTEGER,
+    error TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS bots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    created_by TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- Tokens are stored as SHA-256 hex digests, `rooms` is a comma separated
+-- list of room ids the token may post to ('*' for every room).
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    bot_id INTEGER NOT NULL REFERENCES bots(id),
+    token_hash TEXT NOT NULL UNIQUE,
+    rooms TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER
+);
+
+-- `kind` is 'message' or 'file'.
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    room TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    content TEXT NOT NULL DEFAULT '',
+    filename TEXT,
+    file_id TEXT,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);
+
+-- Client request ids already handled, so resent frames are not posted
+-- twice. message_id stays NULL while the first copy is being handled.
+CREATE TABLE IF NOT EXISTS message_receipts (
+    sender TEXT NOT NULL,
+    request_id TEXT NOT NULL,
+    message_id INTEGER,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (sender, request_id)
+);
+
+CREATE TABLE IF NOT EXISTS direct_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sender TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- Direct messages and mentions that arrived while the recipient had no
+-- connection. `message_id` points into direct_messages for kind 'direct'
+-- and into messages for kind 'mention'.
+CREATE TABLE IF NOT EXISTS offline_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    recipient TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    message_id INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS offline_queue_recipient ON offline_queue (recipient, id);
+-- One row per queued item and device it has been flushed to.
+CREATE TABLE IF NOT EXISTS offline_deliveries (
+    queue_id INTEGER NOT NULL REFERENCES offline_queue(id),
+    device TEXT NOT NULL,
+    delivered_at INTEGER NOT NULL,
+    PRIMARY KEY (queue_id, device)
+);
+
+-- Bytes of uploads stored per user, for the upload quotas.
+CREATE TABLE IF NOT EXISTS storage_usage (
+    username TEXT PRIMARY KEY,
+    bytes INTEGER NOT NULL DEFAULT 0
+);
+
+-- One row per upload. The content is the blob with the same `sha256`.
+-- `uploader` is NULL for old files whose sender is unknown.
+CREATE TABLE IF NOT EXISTS files (
+    id TEXT PRIMARY KEY,
+    original_name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    mime TEXT NOT NULL,
+    uploader TEXT,
+    room TEXT NOT NULL,
+    sha256 TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS files_sha256 ON files (sha256);
+
+-- Stored file contents, shared by every upload with the same hash.
+-- `refcount` is the number of `files` rows with this `sha256`.
+CREATE TABLE IF NOT EXISTS blobs (
+    sha256 TEXT PRIMARY KEY,
+    storage_key TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    refcount INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+-- Dimensions of blobs that are images. `thumbnail_id` is also the
+-- thumbnail's storage key, NULL if the image could not be decoded.
+CREATE TABLE IF NOT EXISTS images (
+    sha256 TEXT PRIMARY KEY REFERENCES blobs(sha256),
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    thumbnail_id TEXT UNIQUE,
+    thumbnail_mime TEXT
+);
+-- Uploads a scanner found to be infected; the files are in the quarantine
+-- directory under `id`.
+CREATE TABLE IF NOT EXISTS quarantine (
+    id TEXT PRIMARY KEY,
+    uploader TEXT NOT NULL,
+    original_name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+";
+
+/// Adds a column unless the table already has it, which databases from
+/// before migrations were tracked may.
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists([table, column])?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    /// A migration failed; none of the pending ones were applied.
+    Failed {
+        version: u32,
+        name: &'static str,
+        error: rusqlite::Error,
+    },
+    /// The database was migrated by a newer version of the server.
+    TooNew { version: u32, latest: u32 },
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::Failed { version, name, error } => write!(f, "migration {} ({}) failed: {}", version, name, error),
+            Error::TooNew { version, latest } => write!(
+                f,
+                "the database is at schema version {}, this server only knows up to {}",
+                version, latest
+            ),
+        }
+    }
+}
+
+/// The database's schema version, 0 for one that has never been migrated.
+fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))
+}
+
+/// Applies every migration the database has not had yet and returns them.
+/// They run in a single transaction, so a failure leaves the schema as it
+/// was; with `dry_run` the transaction is rolled back even if they all
+/// succeed.
+pub fn migrate(conn: &mut Connection, dry_run: bool) -> Result<Vec<&'static Migration>, Error> {
+    // Immediate, so two servers starting at once do not both migrate.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let version = current_version(&tx)?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if version > latest {
+        return Err(Error::TooNew { version, latest });
+    }
+
+    let pending: Vec<_> = MIGRATIONS.iter().filter(|migration| migration.version > version).collect();
+    for migration in &pending {
+        (migration.up)(&tx).map_err(|error| Error::Failed {
+            version: migration.version,
+            name: migration.name,
+            error,
+        })?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.name, crate::db::now()],
+        )?;
+    }
+    if dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+    Ok(pending)
+}
+
+/// Runs `migrate` on a pooled connection.
+pub async fn run(db: &Db, dry_run: bool) -> Result<Vec<&'static Migration>, Error> {
+    db.run(move |conn| Ok(migrate(conn, dry_run))).await?
+}
+
+/// `migrate [--dry-run]`: brings the database up to date and says what was
+/// done, or with `--dry-run` what would be.
+pub async fn command(db: &Db, dry_run: bool) -> Result<(), Error> {
+    let applied = run(db, dry_run).await?;
+    if applied.is_empty() {
+        println!("The database is up to date.");
+        return Ok(());
+    }
+    for migration in &applied {
+        match dry_run {
+            true => println!("Would apply migration {} ({})", migration.version, migration.name),
+            false => println!("Applied migration {} ({})", migration.version, migration.name),
+        }
+    }
+    if dry_run {
+        println!("Dry run, nothing was changed.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database from before anything but accounts was stored.
+    fn users_only() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 username TEXT NOT NULL UNIQUE,
+                 password TEXT NOT NULL
+             );
+             INSERT INTO users (username, password) VALUES ('alice', 'hash-a'), ('bob', 'hash-b');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn tables(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn users(conn: &Connection) -> Vec<(String, String)> {
+        conn.prepare("SELECT username, password FROM users ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn versions(applied: &[&Migration]) -> Vec<u32> {
+        applied.iter().map(|migration| migration.version).collect()
+    }
+
+    fn latest() -> u32 {
+        MIGRATIONS.last().unwrap().version
+    }
+
+    #[test]
+    fn migrates_a_baseline_database_and_keeps_users() {
+        let mut conn = users_only();
+        let applied = migrate(&mut conn, false).unwrap();
+        assert_eq!(versions(&applied), (1..=latest()).collect::<Vec<_>>());
+        assert_eq!(current_version(&conn).unwrap(), latest());
+        assert_eq!(
+            users(&conn),
+            vec![("alice".into(), "hash-a".into()), ("bob".into(), "hash-b".into())]
+        );
+        for table in ["files", "blobs", "images", "messages", "quarantine", "schema_version"] {
+            assert!(tables(&conn).iter().any(|name| name == table), "no {} table", table);
+        }
+        // Migration 2's columns.
+        conn.execute(
+            "INSERT INTO messages (room, kind, sender, created_at, deleted_at, deleted_by) VALUES ('lobby', 'file', 'alice', 0, 1, 'bob')",
+            [],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let mut conn = users_only();
+        let before = tables(&conn);
+        let applied = migrate(&mut conn, true).unwrap();
+        assert_eq!(versions(&applied), (1..=latest()).collect::<Vec<_>>());
+        assert_eq!(tables(&conn), before);
+        assert_eq!(users(&conn).len(), 2);
+
+        // The real run still has everything to do.
+        assert_eq!(migrate(&mut conn, false).unwrap().len(), applied.len());
+    }
+
+    #[test]
+    fn second_run_does_nothing() {
+        let mut conn = users_only();
+        migrate(&mut conn, false).unwrap();
+        assert!(migrate(&mut conn, false).unwrap().is_empty());
+        assert!(migrate(&mut conn, true).unwrap().is_empty());
+        let recorded: u32 = conn.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(recorded, latest());
+    }
+
+    #[test]
+    fn refuses_databases_from_newer_servers() {
+        let mut conn = users_only();
+        migrate(&mut conn, false).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, 'from_the_future', 0)",
+            [latest() + 1],
+        )
+        .unwrap();
+        match migrate(&mut conn, false) {
+            Err(Error::TooNew { version, latest: known }) => assert_eq!((version, known), (latest() + 1, latest())),
+            other => panic!("expected TooNew, got {:?}", other.map(|applied| versions(&applied))),
+        }
+    }
+
+    #[test]
+    fn failed_migrations_leave_the_schema_alone() {
+        let mut conn = users_only();
+        // Takes the name of one of the baseline's indexes.
+        conn.execute_batch("CREATE VIEW messages_room_id AS SELECT 1").unwrap();
+        match migrate(&mut conn, false) {
+            Err(Error::Failed { version, .. }) => assert_eq!(version, 1),
+            other => panic!("expected a failure, got {:?}", other.map(|applied| versions(&applied))),
+        }
+        // Not even the version table was created.
+        assert_eq!(tables(&conn), vec!["users"]);
+    }
+}
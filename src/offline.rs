@@ -0,0 +1,268 @@
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::config::CONFIG;
+use crate::db::Db;
+use crate::protocol::ServerFrame;
+
+/// Longest device id accepted from clients; longer ones are ignored.
+const MAX_DEVICE_LEN: usize = 128;
+/// The device id of connections that did not send one. They all share
+/// it, so each queued message reaches one of them once, however many times
+/// the user logs in.
+const UNNAMED_DEVICE: &str = "";
+
+#[derive(Debug, Clone, Copy)]
+pub enum QueuedKind {
+    Direct,
+    Mention,
+}
+
+impl QueuedKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            QueuedKind::Direct => "direct",
+            QueuedKind::Mention => "mention",
+        }
+    }
+}
+
+/// Picks the device id a connection is tracked under: the one the client
+/// sent, or `UNNAMED_DEVICE` if it did not send a usable one.
+pub fn device_id(requested: Option<&str>) -> String {
+    match requested {
+        Some(device) if !device.is_empty() && device.len() <= MAX_DEVICE_LEN => device.to_string(),
+        _ => UNNAMED_DEVICE.to_string(),
+    }
+}
+
+/// Usernames mentioned as `@name` in a message, without duplicates.
+pub fn mentions(content: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for word in content.split_whitespace() {
+        let name = match word.strip_prefix('@') {
+            Some(rest) => rest.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_'),
+            None => continue,
+        };
+        if !name.is_empty() && !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+pub async fn user_exists(db: &Db, username: &str) -> bool {
+    let username = username.to_string();
+    db.run(move |conn| {
+        conn.query_row("SELECT 1 FROM users WHERE username = ?1", params![username], |_| Ok(()))
+            .optional()
+    })
+    .await
+    .map(|found| found.is_some())
+    .unwrap_or(false)
+}
+
+/// Queues a stored message for `recipient`, who has no open connection.
+pub async fn enqueue(db: &Db, recipient: &str, kind: QueuedKind, message_id: i64) {
+    let name = recipient.to_string();
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "INSERT INTO offline_queue (recipient, kind, message_id, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![name, kind.as_str(), message_id, crate::db::now()],
+            )
+        })
+        .await;
+    if let Err(e) = result {
+        eprintln!("Failed to queue {} {} for {}: {}", kind.as_str(), message_id, recipient, e);
+    }
+}
+
+/// Returns the queued frames `device` has not received yet, oldest first,
+/// and marks them as delivered to it.
+pub async fn flush(db: &Db, recipient: &str, device: &str) -> Vec<ServerFrame> {
+    let (name, device) = (recipient.to_string(), device.to_string());
+    db.run(move |conn| take_undelivered(conn, &name, &device))
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to flush offline queue for {}: {}", recipient, e);
+            Vec::new()
+        })
+}
+
+fn take_undelivered(conn: &mut Connection, recipient: &str, device: &str) -> rusqlite::Result<Vec<ServerFrame>> {
+    // Immediate, so two connections of the same device cannot both take
+    // the same messages.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let frames = take_undelivered_in(&tx, recipient, device)?;
+    tx.commit()?;
+    Ok(frames)
+}
+
+fn take_undelivered_in(conn: &Connection, recipient: &str, device: &str) -> rusqlite::Result<Vec<ServerFrame>> {
+    let now = crate::db::now();
+    let cutoff = now - CONFIG.offline_queue_days * 24 * 60 * 60;
+    conn.execute(
+        "DELETE FROM offline_deliveries
+         WHERE queue_id IN (SELECT id FROM offline_queue WHERE created_at < ?1)",
+        params![cutoff],
+    )?;
+    conn.execute("DELETE FROM offline_queue WHERE created_at < ?1", params![cutoff])?;
+
+    let mut stmt = conn.prepare(
+        "SELECT q.id, q.kind, q.message_id FROM offline_queue q
+         WHERE q.recipient = ?1 AND NOT EXISTS (
+             SELECT 1 FROM offline_deliveries d WHERE d.queue_id = q.id AND d.device = ?2
+         )
+         ORDER BY q.id",
+    )?;
+    let queued = stmt
+        .query_map(params![recipient, device], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut frames = Vec::new();
+    for (queue_id, kind, message_id) in queued {
+        let frame = match kind.as_str() {
+            "direct" => conn
+                .query_row(
+                    "SELECT sender, recipient, content, created_at FROM direct_messages WHERE id = ?1",
+                    params![message_id],
+                    |row| {
+                        Ok(ServerFrame::Direct {
+                            id: Some(message_id),
+                            sender: row.get(0)?,
+                            recipient: row.get(1)?,
+                            content: row.get(2)?,
+                            sent_at: row.get(3)?,
+                        })
+                    },
+                )
+                .optional()?,
+            _ => conn
+                .query_row(
+                    "SELECT sender, content, created_at FROM messages WHERE id = ?1",
+                    params![message_id],
+                    |row| {
+                        Ok(ServerFrame::Message {
+                            id: Some(message_id),
+                            sender: row.get(0)?,
+                            content: row.get(1)?,
+                            sent_at: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?,
+        };
+        conn.execute(
+            "INSERT OR IGNORE INTO offline_deliveries (queue_id, device, delivered_at) VALUES (?1, ?2, ?3)",
+            params![queue_id, device, now],
+        )?;
+        frames.extend(frame);
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mentions() {
+        assert_eq!(mentions("hi @bob and @carol_2!"), ["bob", "carol_2"]);
+        assert_eq!(mentions("@bob, @bob: @Bob?"), ["bob", "Bob"]);
+        assert_eq!(mentions("(@dave) @émile."), ["émile"]);
+        assert_eq!(mentions("mail bob@example.com or @ @! @@"), Vec::<String>::new());
+        assert_eq!(mentions("@@eve"), ["@eve"]);
+    }
+
+    #[test]
+    fn devices_without_an_id_share_one() {
+        assert_eq!(device_id(Some("phone")), "phone");
+        assert_eq!(device_id(None), UNNAMED_DEVICE);
+        assert_eq!(device_id(Some("")), UNNAMED_DEVICE);
+        assert_eq!(device_id(Some(&"x".repeat(MAX_DEVICE_LEN + 1))), UNNAMED_DEVICE);
+    }
+
+    /// Queues a direct message from alice to bob and returns its id.
+    async fn queue(db: &Db, created_at: i64) -> i64 {
+        db.run(move |conn| {
+                conn.execute(
+                    "INSERT INTO direct_messages (sender, recipient, content, created_at) VALUES ('alice', 'bob', 'hi', ?1)",
+                    params![created_at],
+                )?;
+                let id = conn.last_insert_rowid();
+                conn.execute(
+                    "INSERT INTO offline_queue (recipient, kind, message_id, created_at) VALUES ('bob', 'direct', ?1, ?2)",
+                    params![id, created_at],
+                )?;
+                Ok(id)
+            })
+            .await
+            .unwrap()
+    }
+
+    fn ids(frames: &[ServerFrame]) -> Vec<i64> {
+        frames
+            .iter()
+            .map(|frame| match frame {
+                ServerFrame::Direct { id: Some(id), .. } | ServerFrame::Message { id: Some(id), .. } => *id,
+                other => panic!("unexpected frame {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn flushes_once_per_device() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let direct = queue(&db, crate::db::now()).await;
+        let mention = db
+            .run(|conn| {
+                conn.execute(
+                    "INSERT INTO messages (room, kind, sender, content, created_at) VALUES ('lobby', 'text', 'carol', '@bob look', ?1)",
+                    params![crate::db::now()],
+                )?;
+                Ok(conn.last_insert_rowid())
+            })
+            .await
+            .unwrap();
+        enqueue(&db, "bob", QueuedKind::Mention, mention).await;
+
+        let frames = flush(&db, "bob", "phone").await;
+        assert_eq!(ids(&frames), [direct, mention]);
+        assert!(matches!(&frames[1], ServerFrame::Message { sender, .. } if sender == "carol"));
+        assert!(flush(&db, "bob", "phone").await.is_empty());
+
+        // Another device gets its own copy, and so do connections without
+        // a device id, once between them.
+        assert_eq!(ids(&flush(&db, "bob", "laptop").await), [direct, mention]);
+        assert_eq!(ids(&flush(&db, "bob", &device_id(None)).await), [direct, mention]);
+        assert!(flush(&db, "bob", &device_id(None)).await.is_empty());
+
+        // Only new messages arrive afterwards.
+        let later = queue(&db, crate::db::now()).await;
+        assert_eq!(ids(&flush(&db, "bob", "phone").await), [later]);
+        assert!(flush(&db, "alice", "phone").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_purged() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let expired = crate::db::now() - CONFIG.offline_queue_days * 24 * 60 * 60 - 1;
+        queue(&db, expired).await;
+        let current = queue(&db, crate::db::now()).await;
+
+        assert_eq!(ids(&flush(&db, "bob", "phone").await), [current]);
+        let (queued, delivered): (i64, i64) = db
+            .run(|conn| {
+                conn.query_row(
+                    "SELECT (SELECT COUNT(*) FROM offline_queue), (SELECT COUNT(*) FROM offline_deliveries)",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!((queued, delivered), (1, 1));
+    }
+}
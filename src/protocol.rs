@@ -0,0 +1,753 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Protocol versions this server speaks, oldest first.
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// Frames sent by clients. Every frame is a JSON object whose `type` field
+/// selects the variant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Must be the first frame on a WebSocket, always sent as JSON text.
+    /// Lists the protocol versions the client understands and, optionally,
+    /// the encodings it would like the server to use, in order of preference.
+    Hello {
+        versions: Vec<u32>,
+        #[serde(default)]
+        encodings: Vec<String>,
+    },
+    /// `request_id` is generated by the client and unique per user. Frames
+    /// that carry one are acknowledged, and resending an id that was already
+    /// accepted is acknowledged again without posting a second copy.
+    Message {
+        request_id: Option<String>,
+        content: String,
+    },
+    File {
+        request_id: Option<String>,
+        filename: String,
+        /// Base64 in JSON, raw bytes in binary encodings.
+        #[serde(with = "file_bytes")]
+        content: Vec<u8>,
+        /// Whether to remove EXIF and XMP metadata from an image. Absent
+        /// leaves it to the server's default.
+        #[serde(default)]
+        strip_metadata: Option<bool>,
+    },
+    /// Starts a chunked upload of `size` bytes, or resumes the given
+    /// `upload_id` after a reconnect. Answered with `UploadReady`.
+    UploadInit {
+        request_id: Option<String>,
+        upload_id: Option<String>,
+        filename: String,
+        size: u64,
+        /// Hex SHA-256 of the whole file, checked on `UploadFinish`. If the
+        /// server already has this content the file does not need sending.
+        sha256: String,
+        /// As in `File`.
+        #[serde(default)]
+        strip_metadata: Option<bool>,
+    },
+    /// Chunk number `index`, `chunk_size` bytes long except for the last one.
+    /// Answered with `UploadProgress`.
+    UploadChunk {
+        upload_id: String,
+        index: u64,
+        #[serde(with = "file_bytes")]
+        data: Vec<u8>,
+    },
+    /// Verifies the upload and posts the file to the room.
+    UploadFinish {
+        request_id: Option<String>,
+        upload_id: String,
+    },
+    /// A private message to one user, queued for them if they are offline.
+    Direct {
+        request_id: Option<String>,
+        recipient: String,
+        content: String,
+    },
+}
+
+impl ClientFrame {
+    pub fn parse(text: &str) -> Result<ClientFrame, ServerFrame> {
+        serde_json::from_str(text).map_err(|e| {
+            // Still echo the request id when the frame is JSON but not a
+            // valid frame, so the client can tell which request failed.
+            let request_id = serde_json::from_str::<serde_json::Value>(text)
+                .ok()
+                .and_then(|value| value.get("request_id")?.as_str().map(String::from));
+            ServerFrame::error(
+                request_id.as_deref(),
+                ErrorCode::MalformedFrame,
+                format!("Malformed frame: {}", e),
+            )
+        })
+    }
+
+    pub fn decode(data: &[u8], encoding: Encoding) -> Result<ClientFrame, ServerFrame> {
+        let result = match encoding {
+            Encoding::Json => return ClientFrame::parse(&String::from_utf8_lossy(data)),
+            Encoding::MsgPack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader(data).map_err(|e| e.to_string()),
+        };
+        result.map_err(|e| {
+            ServerFrame::error(None, ErrorCode::MalformedFrame, format!("Malformed frame: {}", e))
+        })
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            ClientFrame::Hello { .. } | ClientFrame::UploadChunk { .. } => None,
+            ClientFrame::Message { request_id, .. }
+            | ClientFrame::File { request_id, .. }
+            | ClientFrame::UploadInit { request_id, .. }
+            | ClientFrame::UploadFinish { request_id, .. }
+            | ClientFrame::Direct { request_id, .. } => request_id.as_deref(),
+        }
+    }
+}
+
+/// Frames sent by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Reply to `Hello` with the version and encoding both sides will use.
+    /// Always sent as JSON text; later frames use the chosen encoding.
+    Welcome {
+        version: u32,
+        username: String,
+        encoding: Encoding,
+    },
+    Message {
+        /// History id, absent if the message could not be stored.
+        id: Option<i64>,
+        sender: String,
+        content: String,
+        sent_at: i64,
+    },
+    File {
+        id: Option<i64>,
+        sender: String,
+        file_id: String,
+        filename: String,
+        sent_at: i64,
+        /// Set if the file is an image.
+        image: Option<Box<ImagePreview>>,
+    },
+    /// The file of an earlier `File` message, `id`, has been deleted.
+    /// `deleted_by` is `None` if it expired under the room's retention
+    /// policy.
+    FileDeleted {
+        id: Option<i64>,
+        file_id: String,
+        deleted_by: Option<String>,
+        deleted_at: i64,
+    },
+    /// Reply to `UploadInit`: send chunks starting at `next_chunk`. If that is
+    /// past the end, the server already has the file (or all of it arrived
+    /// before a reconnect) and the client should send `UploadFinish`.
+    UploadReady {
+        request_id: Option<String>,
+        upload_id: String,
+        chunk_size: u64,
+        next_chunk: u64,
+    },
+    /// Reply to each `UploadChunk`.
+    UploadProgress {
+        upload_id: String,
+        received: u64,
+        size: u64,
+    },
+    /// Sent to the recipient's and the sender's connections only.
+    Direct {
+        id: Option<i64>,
+        sender: String,
+        recipient: String,
+        content: String,
+        sent_at: i64,
+    },
+    System { content: String },
+    /// Reply to a client frame that carried a `request_id`.
+    Ack {
+        request_id: String,
+        status: AckStatus,
+        /// History id of the posted message, if it was stored.
+        message_id: Option<i64>,
+        /// The id had already been accepted, so nothing was posted this time.
+        duplicate: bool,
+        /// Why the frame was rejected.
+        code: Option<ErrorCode>,
+        reason: Option<String>,
+    },
+    Error {
+        /// The `request_id` of the client frame being rejected, if any.
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl ServerFrame {
+    pub fn system(content: impl Into<String>) -> ServerFrame {
+        ServerFrame::System {
+            content: content.into(),
+        }
+    }
+
+    pub fn error(request_id: Option<&str>, code: ErrorCode, message: impl Into<String>) -> ServerFrame {
+        ServerFrame::Error {
+            request_id: request_id.map(String::from),
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn accepted(request_id: &str, message_id: Option<i64>, duplicate: bool) -> ServerFrame {
+        ServerFrame::Ack {
+            request_id: request_id.to_string(),
+            status: AckStatus::Accepted,
+            message_id,
+            duplicate,
+            code: None,
+            reason: None,
+        }
+    }
+
+    pub fn rejected(request_id: &str, code: ErrorCode, reason: impl Into<String>) -> ServerFrame {
+        ServerFrame::Ack {
+            request_id: request_id.to_string(),
+            status: AckStatus::Rejected,
+            message_id: None,
+            duplicate: false,
+            code: Some(code),
+            reason: Some(reason.into()),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server frames always serialize")
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Outbound {
+        match encoding {
+            Encoding::Json => Outbound::Text(self.to_json()),
+            // Named fields keep the maps self-describing for other clients.
+            Encoding::MsgPack => Outbound::Binary(
+                rmp_serde::to_vec_named(self).expect("server frames always serialize"),
+            ),
+            Encoding::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(self, &mut data).expect("server frames always serialize");
+                Outbound::Binary(data)
+            }
+        }
+    }
+}
+
+/// Details of an image shared in a `File` frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImagePreview {
+    /// In pixels, as displayed.
+    pub width: u32,
+    pub height: u32,
+    /// Served at `/thumbnails/{thumbnail_id}`, absent if the server could
+    /// not make one.
+    pub thumbnail_id: Option<String>,
+}
+
+/// Wire encodings a WebSocket client can negotiate. SSE and long polling
+/// always use JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Picks the first encoding in the client's preference list that the
+    /// server knows, falling back to JSON.
+    pub fn negotiate(preferences: &[String]) -> Encoding {
+        preferences
+            .iter()
+            .find_map(|name| match name.as_str() {
+                "json" => Some(Encoding::Json),
+                "msgpack" => Some(Encoding::MsgPack),
+                "cbor" => Some(Encoding::Cbor),
+                _ => None,
+            })
+            .unwrap_or(Encoding::Json)
+    }
+}
+
+/// An encoded server frame, ready to be framed by the transport.
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    /// Stored and broadcast to the room.
+    Accepted,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not valid JSON, or not a known frame.
+    MalformedFrame,
+    /// A frame other than `Hello` arrived before the handshake.
+    HandshakeRequired,
+    /// None of the client's versions are supported; the server closes.
+    UnsupportedVersion,
+    /// A valid frame that makes no sense at this point, e.g. a second `Hello`.
+    UnexpectedFrame,
+    /// The request was understood but rejected, e.g. an empty message.
+    InvalidRequest,
+    /// The file is larger than the server's size limit.
+    FileTooLarge,
+    /// Storing the file would exceed the uploader's or the server's quota, or the
+    /// uploader has too many uploads in progress.
+    QuotaExceeded,
+    /// The file's sniffed content type is not accepted.
+    FileTypeNotAllowed,
+    /// The malware scanner flagged the file, which was quarantined.
+    FileInfected,
+    Internal,
+}
+
+/// Picks the newest version both sides support.
+pub fn negotiate(client_versions: &[u32]) -> Option<u32> {
+    SUPPORTED_VERSIONS
+        .iter()
+        .rev()
+        .find(|v| client_versions.contains(v))
+        .copied()
+}
+
+pub fn unsupported_version() -> ServerFrame {
+    ServerFrame::error(
+        None,
+        ErrorCode::UnsupportedVersion,
+        format!("Supported protocol versions: {:?}", SUPPORTED_VERSIONS),
+    )
+}
+
+// File contents are base64 strings in human readable formats (JSON) and
+// plain byte strings in the binary ones, which is the point of using them.
+mod file_bytes {
+    use super::*;
+    use base64::Engine;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+        } else {
+            serializer.serialize_bytes(data)
+        }
+    }
+
+    // Buffered (internally tagged) content does not report whether the
+    // format is human readable, so accept whichever shape arrives.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct FileBytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FileBytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a base64 string or a byte string")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+                base64::engine::general_purpose::STANDARD
+                    .decode(v)
+                    .map_err(|e| E::custom(format!("file content must be base64: {}", e)))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(v)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    data.push(byte);
+                }
+                Ok(data)
+            }
+        }
+
+        deserializer.deserialize_any(FileBytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn client_frames() -> Vec<ClientFrame> {
+        vec![
+            ClientFrame::Hello {
+                versions: vec![1],
+                encodings: vec!["msgpack".into(), "json".into()],
+            },
+            ClientFrame::Message {
+                request_id: Some("r1".into()),
+                content: "hello".into(),
+            },
+            ClientFrame::File {
+                request_id: None,
+                filename: "notes.txt".into(),
+                content: b"some notes\n".to_vec(),
+                strip_metadata: Some(true),
+            },
+            ClientFrame::UploadInit {
+                request_id: Some("r2".into()),
+                upload_id: None,
+                filename: "video.mp4".into(),
+                size: 10 << 20,
+                sha256: "ab".repeat(32),
+                strip_metadata: None,
+            },
+            ClientFrame::UploadChunk {
+                upload_id: "u1".into(),
+                index: 3,
+                data: vec![0, 1, 2, 255],
+            },
+            ClientFrame::UploadFinish {
+                request_id: Some("r3".into()),
+                upload_id: "u1".into(),
+            },
+            ClientFrame::Direct {
+                request_id: Some("r4".into()),
+                recipient: "bob".into(),
+                content: "psst".into(),
+            },
+        ]
+    }
+
+    fn server_frames() -> Vec<ServerFrame> {
+        vec![
+            ServerFrame::Welcome {
+                version: 1,
+                username: "alice".into(),
+                encoding: Encoding::Cbor,
+            },
+            ServerFrame::Message {
+                id: Some(1),
+                sender: "alice".into(),
+                content: "hello".into(),
+                sent_at: 1_700_000_000,
+            },
+            ServerFrame::File {
+                id: Some(2),
+                sender: "alice".into(),
+                file_id: "f1".into(),
+                filename: "cat.png".into(),
+                sent_at: 1_700_000_000,
+                image: Some(Box::new(ImagePreview {
+                    width: 640,
+                    height: 480,
+                    thumbnail_id: Some("t1".into()),
+                })),
+            },
+            ServerFrame::FileDeleted {
+                id: Some(2),
+                file_id: "f1".into(),
+                deleted_by: None,
+                deleted_at: 1_700_000_100,
+            },
+            ServerFrame::UploadReady {
+                request_id: Some("r2".into()),
+                upload_id: "u1".into(),
+                chunk_size: 1 << 20,
+                next_chunk: 0,
+            },
+            ServerFrame::UploadProgress {
+                upload_id: "u1".into(),
+                received: 4,
+                size: 10,
+            },
+            ServerFrame::Direct {
+                id: None,
+                sender: "alice".into(),
+                recipient: "bob".into(),
+                content: "psst".into(),
+                sent_at: 1_700_000_000,
+            },
+            ServerFrame::system("bob has joined the chat."),
+            ServerFrame::accepted("r1", Some(1), false),
+            ServerFrame::rejected("r1", ErrorCode::InvalidRequest, "Empty message"),
+            ServerFrame::error(None, ErrorCode::Internal, "oops"),
+        ]
+    }
+
+    // Frames have no `PartialEq`, so compare them as JSON values.
+    fn value<T: Serialize>(frame: &T) -> Value {
+        serde_json::to_value(frame).unwrap()
+    }
+
+    #[test]
+    fn client_frames_round_trip() {
+        for frame in client_frames() {
+            let text = serde_json::to_string(&frame).unwrap();
+            let parsed = ClientFrame::parse(&text).unwrap_or_else(|e| panic!("{}: {:?}", text, e));
+            assert_eq!(value(&parsed), value(&frame));
+        }
+    }
+
+    #[test]
+    fn server_frames_round_trip() {
+        for frame in server_frames() {
+            let text = frame.to_json();
+            let parsed: ServerFrame = serde_json::from_str(&text).unwrap();
+            assert_eq!(value(&parsed), value(&frame));
+        }
+    }
+
+    #[test]
+    fn frames_are_tagged_by_type() {
+        let message: Value = serde_json::from_str(&ServerFrame::system("hi").to_json()).unwrap();
+        assert_eq!(message, serde_json::json!({ "type": "system", "content": "hi" }));
+
+        let frame = ClientFrame::parse(r#"{"type":"upload_finish","upload_id":"u1"}"#).unwrap();
+        assert!(matches!(frame, ClientFrame::UploadFinish { request_id: None, .. }));
+    }
+
+    fn error_of(frame: ServerFrame) -> (Option<String>, ErrorCode) {
+        match frame {
+            ServerFrame::Error { request_id, code, .. } => (request_id, code),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        for text in ["", "not json", "[1, 2]", r#"{"content":"no type"}"#, r#"{"type":"message"}"#] {
+            let (request_id, code) = error_of(ClientFrame::parse(text).unwrap_err());
+            assert_eq!(request_id, None, "{}", text);
+            assert_eq!(code, ErrorCode::MalformedFrame);
+        }
+    }
+
+    #[test]
+    fn malformed_frames_echo_the_request_id() {
+        // Missing field.
+        let frame = ClientFrame::parse(r#"{"type":"message","request_id":"r1"}"#).unwrap_err();
+        assert_eq!(error_of(frame), (Some("r1".into()), ErrorCode::MalformedFrame));
+
+        // Unknown frame type.
+        let frame = ClientFrame::parse(r#"{"type":"shout","request_id":"r2","content":"hi"}"#).unwrap_err();
+        assert_eq!(error_of(frame), (Some("r2".into()), ErrorCode::MalformedFrame));
+
+        // A request id that is not a string is not echoed.
+        let frame = ClientFrame::parse(r#"{"type":"message","request_id":7}"#).unwrap_err();
+        assert_eq!(error_of(frame), (None, ErrorCode::MalformedFrame));
+    }
+
+    #[test]
+    fn request_id_is_read_from_every_frame_that_has_one() {
+        let ids: Vec<_> = client_frames().iter().map(|f| f.request_id().map(String::from)).collect();
+        let expected = [None, Some("r1"), None, Some("r2"), None, Some("r3"), Some("r4")];
+        assert_eq!(ids, expected.map(|id| id.map(String::from)));
+    }
+
+    #[test]
+    fn error_codes_serialize_in_snake_case() {
+        let codes = [
+            (ErrorCode::MalformedFrame, "malformed_frame"),
+            (ErrorCode::HandshakeRequired, "handshake_required"),
+            (ErrorCode::UnsupportedVersion, "unsupported_version"),
+            (ErrorCode::UnexpectedFrame, "unexpected_frame"),
+            (ErrorCode::InvalidRequest, "invalid_request"),
+            (ErrorCode::FileTooLarge, "file_too_large"),
+            (ErrorCode::QuotaExceeded, "quota_exceeded"),
+            (ErrorCode::FileTypeNotAllowed, "file_type_not_allowed"),
+            (ErrorCode::FileInfected, "file_infected"),
+            (ErrorCode::Internal, "internal"),
+        ];
+        for (code, name) in codes {
+            assert_eq!(value(&code), serde_json::json!(name));
+            assert_eq!(serde_json::from_value::<ErrorCode>(serde_json::json!(name)).unwrap(), code);
+        }
+
+        let ack = value(&ServerFrame::rejected("r1", ErrorCode::FileTooLarge, "Too big"));
+        assert_eq!(
+            ack,
+            serde_json::json!({
+                "type": "ack",
+                "request_id": "r1",
+                "status": "rejected",
+                "message_id": null,
+                "duplicate": false,
+                "code": "file_too_large",
+                "reason": "Too big",
+            })
+        );
+    }
+
+    #[test]
+    fn negotiates_the_newest_shared_version() {
+        assert_eq!(negotiate(&[1, 2]), Some(1));
+        assert_eq!(negotiate(&[2]), None);
+        assert_eq!(negotiate(&[]), None);
+        assert_eq!(error_of(unsupported_version()).1, ErrorCode::UnsupportedVersion);
+    }
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MsgPack, Encoding::Cbor];
+
+    /// Encodes a client frame the way a client using `encoding` would.
+    fn client_encode(frame: &ClientFrame, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Json => serde_json::to_vec(frame).unwrap(),
+            Encoding::MsgPack => rmp_serde::to_vec_named(frame).unwrap(),
+            Encoding::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(frame, &mut data).unwrap();
+                data
+            }
+        }
+    }
+
+    /// Decodes a server frame the way a client using `encoding` would.
+    fn client_decode(outbound: Outbound, encoding: Encoding) -> ServerFrame {
+        match (outbound, encoding) {
+            (Outbound::Text(text), Encoding::Json) => serde_json::from_str(&text).unwrap(),
+            (Outbound::Binary(data), Encoding::MsgPack) => rmp_serde::from_slice(&data).unwrap(),
+            (Outbound::Binary(data), Encoding::Cbor) => ciborium::from_reader(&data[..]).unwrap(),
+            (outbound, encoding) => panic!("{:?} sent as {:?}", encoding, outbound),
+        }
+    }
+
+    #[test]
+    fn client_frames_round_trip_in_every_encoding() {
+        for encoding in ENCODINGS {
+            for frame in client_frames() {
+                let data = client_encode(&frame, encoding);
+                let decoded = ClientFrame::decode(&data, encoding)
+                    .unwrap_or_else(|e| panic!("{:?} {:?}: {:?}", encoding, frame, e));
+                assert_eq!(value(&decoded), value(&frame), "{:?}", encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn server_frames_round_trip_in_every_encoding() {
+        for encoding in ENCODINGS {
+            for frame in server_frames() {
+                let decoded = client_decode(frame.encode(encoding), encoding);
+                assert_eq!(value(&decoded), value(&frame), "{:?}", encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_binary_frames_are_rejected() {
+        for encoding in [Encoding::MsgPack, Encoding::Cbor] {
+            for data in [&b""[..], b"\xff\xff", b"hello"] {
+                let (request_id, code) = error_of(ClientFrame::decode(data, encoding).unwrap_err());
+                assert_eq!((request_id, code), (None, ErrorCode::MalformedFrame), "{:?}", encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn negotiates_the_first_known_encoding() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(Encoding::negotiate(&names(&["cbor", "msgpack"])), Encoding::Cbor);
+        assert_eq!(Encoding::negotiate(&names(&["protobuf", "msgpack", "json"])), Encoding::MsgPack);
+        assert_eq!(Encoding::negotiate(&names(&["json", "cbor"])), Encoding::Json);
+        // Unknown names, and names in the wrong case, fall back to JSON.
+        assert_eq!(Encoding::negotiate(&names(&["protobuf", "MsgPack"])), Encoding::Json);
+        assert_eq!(Encoding::negotiate(&[]), Encoding::Json);
+
+        let welcome = ServerFrame::Welcome {
+            version: 1,
+            username: "alice".into(),
+            encoding: Encoding::MsgPack,
+        };
+        assert_eq!(value(&welcome)["encoding"], "msgpack");
+    }
+
+    fn chunk(data: &[u8]) -> ClientFrame {
+        ClientFrame::UploadChunk {
+            upload_id: "u1".into(),
+            index: 0,
+            data: data.to_vec(),
+        }
+    }
+
+    fn chunk_data(frame: ClientFrame) -> Vec<u8> {
+        match frame {
+            ClientFrame::UploadChunk { data, .. } => data,
+            other => panic!("expected a chunk, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_bytes_are_base64_in_json() {
+        let data = [0, 1, 2, 254, 255];
+        assert_eq!(value(&chunk(&data))["data"], "AAEC/v8=");
+
+        let frame = ClientFrame::parse(r#"{"type":"upload_chunk","upload_id":"u1","index":0,"data":"AAEC/v8="}"#);
+        assert_eq!(chunk_data(frame.unwrap()), data);
+
+        let frame = ClientFrame::parse(r#"{"type":"upload_chunk","upload_id":"u1","index":0,"data":"not base64!"}"#);
+        assert_eq!(error_of(frame.unwrap_err()).1, ErrorCode::MalformedFrame);
+    }
+
+    #[test]
+    fn file_bytes_are_raw_in_binary_encodings() {
+        let data: Vec<u8> = (0..=255).collect();
+
+        // A msgpack bin value: 0xc5 and a two byte length, then the bytes.
+        let encoded = client_encode(&chunk(&data), Encoding::MsgPack);
+        assert!(encoded.windows(3 + data.len()).any(|w| w[..3] == [0xc5, 0x01, 0x00] && w[3..] == data[..]));
+        assert_eq!(chunk_data(ClientFrame::decode(&encoded, Encoding::MsgPack).unwrap()), data);
+
+        // A CBOR byte string: major type 2 with a two byte length.
+        let encoded = client_encode(&chunk(&data), Encoding::Cbor);
+        assert!(encoded.windows(3 + data.len()).any(|w| w[..3] == [0x59, 0x01, 0x00] && w[3..] == data[..]));
+        assert_eq!(chunk_data(ClientFrame::decode(&encoded, Encoding::Cbor).unwrap()), data);
+    }
+
+    #[test]
+    fn file_bytes_accept_other_shapes_in_binary_encodings() {
+        #[derive(Serialize)]
+        struct Chunk<T> {
+            r#type: &'static str,
+            upload_id: &'static str,
+            index: u64,
+            data: T,
+        }
+        fn chunk<T>(data: T) -> Chunk<T> {
+            Chunk {
+                r#type: "upload_chunk",
+                upload_id: "u1",
+                index: 0,
+                data,
+            }
+        }
+
+        // Clients whose msgpack library has no byte type send an array, and
+        // some send base64 like they would in JSON.
+        let array = rmp_serde::to_vec_named(&chunk(vec![1u8, 2, 3])).unwrap();
+        assert_eq!(chunk_data(ClientFrame::decode(&array, Encoding::MsgPack).unwrap()), [1, 2, 3]);
+
+        let mut base64 = Vec::new();
+        ciborium::into_writer(&chunk("AQID"), &mut base64).unwrap();
+        assert_eq!(chunk_data(ClientFrame::decode(&base64, Encoding::Cbor).unwrap()), [1, 2, 3]);
+    }
+}
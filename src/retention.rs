@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use rusqlite::params;
+use uuid::Uuid;
+
+use crate::chat::{self, Clients};
+use crate::config::CONFIG;
+use crate::db::Db;
+use crate::handling_files;
+use crate::storage::{Storage, STORAGE};
+
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically deletes files older than their room's retention period,
+/// cleans up stored content nothing refers to any more and forgets old
+/// message request ids.
+pub fn spawn_file_janitor(clients: Clients, db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(JANITOR_INTERVAL);
+        // Orphans seen on the previous pass. Something is only removed once
+        // it has been an orphan for a whole interval, so content that is
+        // being stored right now (object written, rows not yet) is safe.
+        let mut suspects = Orphans::default();
+        loop {
+            interval.tick().await;
+            expire_files(&clients, &db, |room| CONFIG.retention_days(room)).await;
+            suspects = remove_orphans(&db, &**STORAGE, suspects).await;
+            chat::prune_receipts(&db).await;
+        }
+    });
+}
+
+/// Deletes the files that have been kept for longer than `retention_days`
+/// of their room, if it has a retention period.
+async fn expire_files(clients: &Clients, db: &Db, retention_days: fn(&str) -> Option<i64>) {
+    let now = crate::db::now();
+    let expired: rusqlite::Result<Vec<String>> = db
+        .run(move |conn| {
+            let rooms = conn
+                .prepare("SELECT DISTINCT room FROM files")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut expired = Vec::new();
+            for room in rooms {
+                if let Some(days) = retention_days(&room) {
+                    let mut stmt = conn.prepare("SELECT id FROM files WHERE room = ?1 AND created_at < ?2")?;
+                    let ids = stmt.query_map(params![room, now - days * 24 * 60 * 60], |row| row.get(0))?;
+                    for id in ids {
+                        expired.push(id?);
+                    }
+                }
+            }
+            Ok(expired)
+        })
+        .await;
+
+    let expired = match expired {
+        Ok(expired) => expired,
+        Err(e) => {
+            eprintln!("Failed to find expired files: {}", e);
+            return;
+        }
+    };
+    for file_id in expired {
+        if let Err(e) = chat::publish_file_deletion(clients, db, &file_id, None).await {
+            eprintln!("Failed to delete expired file {}: {}", file_id, e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Orphans {
+    /// Blobs no file refers to, by hash.
+    blobs: HashSet<String>,
+    /// Storage keys no blob or thumbnail uses.
+    objects: HashSet<String>,
+}
+
+/// Removes the orphans in `suspects` that are still orphans, and returns
+/// the orphans found this time. `storage` is where blobs are kept.
+async fn remove_orphans(db: &Db, storage: &dyn Storage, suspects: Orphans) -> Orphans {
+    let mut orphans = Orphans::default();
+
+    let blobs: rusqlite::Result<Vec<String>> = db
+        .run(|conn| {
+            conn.prepare(
+                "SELECT sha256 FROM blobs
+                 WHERE NOT EXISTS (SELECT 1 FROM files WHERE files.sha256 = blobs.sha256)",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect()
+        })
+        .await;
+    match blobs {
+        Ok(blobs) => {
+            for sha256 in blobs {
+                if !suspects.blobs.contains(&sha256) {
+                    orphans.blobs.insert(sha256);
+                } else if handling_files::remove_orphan_blob(db, &sha256).await {
+                    println!("Removed orphan blob {}", sha256);
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to find orphan blobs: {}", e),
+    }
+
+    let keys = match storage.list().await {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Failed to list stored objects: {}", e);
+            return orphans;
+        }
+    };
+    let used: rusqlite::Result<HashSet<String>> = db
+        .run(|conn| {
+            conn.prepare(
+                "SELECT storage_key FROM blobs
+                 UNION SELECT thumbnail_id FROM images WHERE thumbnail_id IS NOT NULL",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect()
+        })
+        .await;
+    let used = match used {
+        Ok(used) => used,
+        Err(e) => {
+            eprintln!("Failed to find stored objects in use: {}", e);
+            return orphans;
+        }
+    };
+    // Only keys the server could have made; anything else sharing the
+    // directory or bucket is left alone.
+    for key in keys {
+        if used.contains(&key) || Uuid::parse_str(&key).is_err() {
+            continue;
+        }
+        if !suspects.objects.contains(&key) {
+            orphans.objects.insert(key);
+        } else if let Err(e) = storage.delete(&key).await {
+            eprintln!("Failed to delete orphan object {}: {}", key, e);
+        } else {
+            println!("Removed orphan object {}", key);
+        }
+    }
+    orphans
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    async fn add_file(db: &Db, id: &'static str, sha256: &'static str, age_days: i64) {
+        db.run(move |conn| {
+            let created_at = crate::db::now() - age_days * DAY;
+            conn.execute(
+                "INSERT INTO files (id, original_name, size, mime, uploader, room, sha256, created_at)
+                 VALUES (?1, 'notes.txt', 5, 'text/plain', NULL, 'lobby', ?2, ?3)",
+                params![id, sha256, created_at],
+            )?;
+            conn.execute(
+                "INSERT INTO messages (room, kind, sender, filename, file_id, created_at)
+                 VALUES ('lobby', 'file', 'alice', 'notes.txt', ?1, ?2)",
+                params![id, created_at],
+            )?;
+            conn.execute(
+                "INSERT INTO blobs (sha256, storage_key, size, refcount, created_at) VALUES (?1, ?2, 5, 1, 0)
+                 ON CONFLICT (sha256) DO UPDATE SET refcount = refcount + 1",
+                params![sha256, Uuid::new_v4().to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn column(db: &Db, sql: &'static str) -> Vec<String> {
+        db.run(move |conn| conn.prepare(sql)?.query_map([], |row| row.get(0))?.collect())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expires_files_but_keeps_shared_blobs() {
+        let (_dir, db) = crate::db::open_temp().await;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        add_file(&db, "old", "shared", 10).await;
+        add_file(&db, "new", "shared", 1).await;
+        add_file(&db, "old-alone", "alone", 10).await;
+
+        // Rooms without a retention period keep everything.
+        expire_files(&clients, &db, |_| None).await;
+        assert_eq!(column(&db, "SELECT id FROM files ORDER BY id").await, ["new", "old", "old-alone"]);
+
+        expire_files(&clients, &db, |room| (room == "lobby").then_some(7)).await;
+        assert_eq!(column(&db, "SELECT id FROM files").await, ["new"]);
+        assert_eq!(column(&db, "SELECT sha256 || ':' || refcount FROM blobs").await, ["shared:1"]);
+        // Their messages stay, marked as deleted by nobody.
+        assert_eq!(
+            column(
+                &db,
+                "SELECT file_id FROM messages WHERE deleted_at IS NOT NULL AND deleted_by IS NULL ORDER BY file_id"
+            )
+            .await,
+            ["old", "old-alone"]
+        );
+    }
+
+    #[tokio::test]
+    async fn removes_orphans_on_the_second_pass() {
+        let (dir, db) = crate::db::open_temp().await;
+        let objects = dir.path().join("objects");
+        let storage = LocalStorage::new(&objects);
+
+        add_file(&db, "kept", "kept", 0).await;
+        db.run(|conn| {
+            conn.execute(
+                "INSERT INTO blobs (sha256, storage_key, size, refcount, created_at) VALUES ('orphan', 'k', 5, 1, 0)",
+                [],
+            )
+        })
+        .await
+        .unwrap();
+        let kept_key = column(&db, "SELECT storage_key FROM blobs WHERE sha256 = 'kept'").await.remove(0);
+        let stray = Uuid::new_v4().to_string();
+        create_objects(&objects, &[&kept_key, &stray, "README"]).await;
+
+        // The first pass only takes note.
+        let suspects = remove_orphans(&db, &storage, Orphans::default()).await;
+        assert!(suspects.blobs.contains("orphan"));
+        assert!(suspects.objects.contains(&stray));
+        assert_eq!(column(&db, "SELECT sha256 FROM blobs ORDER BY sha256").await, ["kept", "orphan"]);
+        assert_eq!(listed(&storage).await, sorted(&[&kept_key, &stray, "README"]));
+
+        // Orphans that show up in between wait for the next pass.
+        db.run(|conn| {
+            conn.execute(
+                "INSERT INTO blobs (sha256, storage_key, size, refcount, created_at) VALUES ('late', 'l', 5, 1, 0)",
+                [],
+            )
+        })
+        .await
+        .unwrap();
+        let late = Uuid::new_v4().to_string();
+        create_objects(&objects, &[&late]).await;
+
+        let suspects = remove_orphans(&db, &storage, suspects).await;
+        assert_eq!(column(&db, "SELECT sha256 FROM blobs ORDER BY sha256").await, ["kept", "late"]);
+        assert_eq!(listed(&storage).await, sorted(&[&kept_key, &late, "README"]));
+        assert!(suspects.blobs.contains("late") && suspects.objects.contains(&late));
+    }
+
+    /// Creates `dir` with an empty file for each of `names`.
+    async fn create_objects(dir: &std::path::Path, names: &[&str]) {
+        tokio::fs::create_dir_all(dir).await.unwrap();
+        for name in names {
+            tokio::fs::write(dir.join(name), b"").await.unwrap();
+        }
+    }
+
+    async fn listed(storage: &LocalStorage) -> Vec<String> {
+        let mut keys = storage.list().await.unwrap();
+        keys.sort();
+        keys
+    }
+
+    fn sorted(keys: &[&str]) -> Vec<String> {
+        let mut keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+}
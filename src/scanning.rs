@@ -0,0 +1,276 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use rusqlite::params;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use uuid::Uuid;
+
+use crate::config::CONFIG;
+use crate::db::Db;
+
+/// Infected uploads are moved here, on the server's own disk whatever the
+/// storage backend, for an administrator to look at.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Bytes sent to clamd per `INSTREAM` chunk.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+/// How long a single scan may take, including sending the file.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// What a scanner made of a file.
+#[derive(Debug)]
+pub enum Verdict {
+    Clean,
+    /// The name of the signature that matched.
+    Infected(String),
+}
+
+/// Checks uploads for malware before they are stored and shared.
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// Scans the complete file at `path`. An error means the file could not
+    /// be checked, not that it is infected.
+    async fn scan(&self, path: &Path) -> io::Result<Verdict>;
+
+    /// Shown at startup.
+    fn describe(&self) -> String;
+}
+
+lazy_static! {
+    /// The scanner picked with `CHAT_SCANNER`, if any.
+    pub static ref SCANNER: Option<Box<dyn Scanner>> = from_config();
+}
+
+fn from_config() -> Option<Box<dyn Scanner>> {
+    match CONFIG.scanner.as_str() {
+        "none" => None,
+        "clamd" => Some(Box::new(Clamd::new(&CONFIG.clamd_address))),
+        other => panic!("Unknown scanner {:?}, expected \"none\" or \"clamd\"", other),
+    }
+}
+
+pub fn enabled() -> bool {
+    SCANNER.is_some()
+}
+
+/// A ClamAV daemon, reached over TCP (`host:port`) or a Unix socket (a path
+/// starting with `/`). Files are sent with `INSTREAM`, so clamd does not
+/// need access to the server's disk; they must be within its
+/// `StreamMaxLength`.
+pub struct Clamd {
+    address: String,
+}
+
+impl Clamd {
+    pub fn new(address: &str) -> Clamd {
+        Clamd {
+            address: address.to_string(),
+        }
+    }
+
+    async fn scan_with_timeout(&self, path: &Path) -> io::Result<Verdict> {
+        let mut file = fs::File::open(path).await?;
+        if self.address.starts_with('/') {
+            instream(UnixStream::connect(&self.address).await?, &mut file).await
+        } else {
+            instream(TcpStream::connect(&self.address).await?, &mut file).await
+        }
+    }
+}
+
+#[async_trait]
+impl Scanner for Clamd {
+    async fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        match tokio::time::timeout(SCAN_TIMEOUT, self.scan_with_timeout(path)).await {
+            Ok(verdict) => verdict,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "clamd did not answer in time")),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("clamd at {}", self.address)
+    }
+}
+
+/// Streams `file` to clamd and reads its verdict: each chunk is prefixed
+/// with its length, and an empty chunk ends the file.
+async fn instream<S, R>(mut socket: S, file: &mut R) -> io::Result<Verdict>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    socket.write_all(b"zINSTREAM\0").await?;
+    let mut chunk = vec![0; CLAMD_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk).await?;
+        socket.write_all(&(read as u32).to_be_bytes()).await?;
+        if read == 0 {
+            break;
+        }
+        socket.write_all(&chunk[..read]).await?;
+    }
+    socket.flush().await?;
+
+    let mut reply = Vec::new();
+    socket.read_to_end(&mut reply).await?;
+    parse_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// `stream: OK`, `stream: <signature> FOUND` or `<reason> ERROR`.
+fn parse_reply(reply: &str) -> io::Result<Verdict> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else {
+        Err(io::Error::other(format!("clamd answered {:?}", reply)))
+    }
+}
+
+/// Moves the infected file at `path` out of the way and records where it
+/// came from.
+pub async fn quarantine(
+    db: &Db,
+    uploader: &str,
+    filename: &str,
+    sha256: &str,
+    signature: &str,
+    path: &Path,
+) -> io::Result<()> {
+    let id = Uuid::new_v4().to_string();
+    let size = fs::metadata(path).await?.len();
+    fs::create_dir_all(QUARANTINE_DIR).await?;
+    fs::rename(path, PathBuf::from(QUARANTINE_DIR).join(&id)).await?;
+
+    let row = (id.clone(), uploader.to_string(), filename.to_string(), sha256.to_string(), signature.to_string());
+    db.run(move |conn| {
+        let (id, uploader, filename, sha256, signature) = row;
+        conn.execute(
+            "INSERT INTO quarantine (id, uploader, original_name, size, sha256, signature, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![id, uploader, filename, size, sha256, signature, crate::db::now()],
+        )
+    })
+    .await
+    .map_err(io::Error::other)?;
+    println!("Quarantined {} from {} as {}: {}", filename, uploader, id, signature);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, UnixListener};
+
+    /// What a fake clamd received on one connection: the command and the
+    /// length of every chunk, then the data they added up to.
+    #[derive(Debug, Default)]
+    struct Received {
+        command: Vec<u8>,
+        chunks: Vec<usize>,
+        data: Vec<u8>,
+    }
+
+    /// Reads one `INSTREAM` request, answers `reply` and hangs up, like
+    /// clamd does.
+    async fn serve_instream<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, reply: &str) -> Received {
+        let mut received = Received {
+            command: vec![0; b"zINSTREAM\0".len()],
+            ..Default::default()
+        };
+        socket.read_exact(&mut received.command).await.unwrap();
+        loop {
+            let len = socket.read_u32().await.unwrap() as usize;
+            received.chunks.push(len);
+            if len == 0 {
+                break;
+            }
+            let start = received.data.len();
+            received.data.resize(start + len, 0);
+            socket.read_exact(&mut received.data[start..]).await.unwrap();
+        }
+        socket.write_all(reply.as_bytes()).await.unwrap();
+        received
+    }
+
+    async fn scan_over_tcp(path: &Path, reply: &'static str) -> (io::Result<Verdict>, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let clamd = Clamd::new(&listener.local_addr().unwrap().to_string());
+        let server = tokio::spawn(async move { serve_instream(listener.accept().await.unwrap().0, reply).await });
+        let verdict = clamd.scan(path).await;
+        (verdict, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn streams_files_in_length_prefixed_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload");
+        let data: Vec<u8> = (0..2 * CLAMD_CHUNK_SIZE + 1000).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let (verdict, received) = scan_over_tcp(&path, "stream: OK\0").await;
+        assert!(matches!(verdict, Ok(Verdict::Clean)), "{:?}", verdict);
+        assert_eq!(received.command, b"zINSTREAM\0");
+        assert_eq!(received.chunks, vec![CLAMD_CHUNK_SIZE, CLAMD_CHUNK_SIZE, 1000, 0]);
+        assert_eq!(received.data, data);
+
+        // An empty file is just the terminating chunk.
+        std::fs::write(&path, b"").unwrap();
+        let (_, received) = scan_over_tcp(&path, "stream: OK\0").await;
+        assert_eq!(received.chunks, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn reads_clamd_verdicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload");
+        std::fs::write(&path, b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR").unwrap();
+
+        let (verdict, _) = scan_over_tcp(&path, "stream: Eicar-Test-Signature FOUND\0").await;
+        assert!(matches!(verdict, Ok(Verdict::Infected(ref signature)) if signature == "Eicar-Test-Signature"));
+        let (verdict, _) = scan_over_tcp(&path, "INSTREAM size limit exceeded. ERROR\0").await;
+        let error = verdict.err().unwrap();
+        assert!(error.to_string().contains("size limit exceeded"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn scans_over_unix_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload");
+        std::fs::write(&path, b"hello").unwrap();
+        let socket = dir.path().join("clamd.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let clamd = Clamd::new(socket.to_str().unwrap());
+        let server = tokio::spawn(async move { serve_instream(listener.accept().await.unwrap().0, "stream: OK\0").await });
+
+        assert!(matches!(clamd.scan(&path).await, Ok(Verdict::Clean)));
+        assert_eq!(server.await.unwrap().data, b"hello");
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert!(matches!(parse_reply("stream: OK\0"), Ok(Verdict::Clean)));
+        assert!(matches!(parse_reply("stream: OK\n"), Ok(Verdict::Clean)));
+        assert!(matches!(parse_reply("OK"), Ok(Verdict::Clean)));
+        assert!(matches!(parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0"), Ok(Verdict::Infected(ref s)) if s == "Win.Test.EICAR_HDB-1"));
+        assert!(parse_reply("Can't allocate memory ERROR\0").is_err());
+        assert!(parse_reply("").is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_when_clamd_is_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload");
+        std::fs::write(&path, b"hello").unwrap();
+        assert!(Clamd::new(&address).scan(&path).await.is_err());
+    }
+}
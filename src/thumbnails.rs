@@ -0,0 +1,94 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+
+/// Longest side of a thumbnail, in pixels.
+const THUMBNAIL_SIZE: u32 = 320;
+const JPEG_QUALITY: u8 = 80;
+/// Bigger images are not decoded, only their dimensions are recorded.
+const MAX_DIMENSION: u32 = 16384;
+const MAX_DECODE_ALLOC: u64 = 512 << 20;
+
+/// Sniffed types that get a thumbnail. These are also the types that are
+/// safe to show inline: plain raster formats a browser never runs scripts
+/// from, unlike SVG.
+const IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+pub fn is_image(mime: &str) -> bool {
+    IMAGE_TYPES.contains(&mime)
+}
+
+/// What was learned from an uploaded image.
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /// The encoded thumbnail and its type, unless the image could not be
+    /// decoded.
+    pub thumbnail: Option<(Vec<u8>, &'static str)>,
+}
+
+/// Reads the dimensions of the image at `path` and makes a thumbnail of it.
+/// Returns `None` if it is not an image the server can read.
+pub async fn analyze(path: PathBuf) -> Option<ImageInfo> {
+    match tokio::task::spawn_blocking(move || analyze_blocking(&path)).await {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Thumbnail task failed: {}", e);
+            None
+        }
+    }
+}
+
+fn analyze_blocking(path: &Path) -> Option<ImageInfo> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = ImageReader::open(path).ok()?.with_guessed_format().ok()?;
+    reader.no_limits();
+    let mut decoder = reader.into_decoder().ok()?;
+    let (width, height) = decoder.dimensions();
+    let orientation = decoder.orientation().ok();
+    // Rotated by EXIF, so report what a viewer shows.
+    let (width, height) = match orientation.map(|o| o.to_exif()) {
+        Some(5..=8) => (height, width),
+        _ => (width, height),
+    };
+
+    let mut info = ImageInfo {
+        width,
+        height,
+        thumbnail: None,
+    };
+    if decoder.set_limits(limits).is_err() {
+        return Some(info);
+    }
+    let mut image = match DynamicImage::from_decoder(decoder) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Not making a thumbnail of {}: {}", path.display(), e);
+            return Some(info);
+        }
+    };
+    if let Some(orientation) = orientation {
+        image.apply_orientation(orientation);
+    }
+
+    info.thumbnail = encode(&image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE));
+    Some(info)
+}
+
+/// PNG if the image has transparency, JPEG otherwise.
+fn encode(thumbnail: &DynamicImage) -> Option<(Vec<u8>, &'static str)> {
+    let mut data = Vec::new();
+    if thumbnail.color().has_alpha() {
+        thumbnail.write_to(&mut Cursor::new(&mut data), ImageFormat::Png).ok()?;
+        Some((data, "image/png"))
+    } else {
+        let encoder = JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY);
+        thumbnail.to_rgb8().write_with_encoder(encoder).ok()?;
+        Some((data, "image/jpeg"))
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::sse::Event;
+
+use crate::auth::{json_message, session_user};
+use crate::chat::{self, Clients};
+use crate::db::Db;
+use crate::offline;
+use crate::protocol::{self, AckStatus, Encoding, Outbound, ServerFrame, SUPPORTED_VERSIONS};
+
+/// How long a poll request waits for the first message.
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// Pollers that have not come back for this long are considered gone.
+const POLL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const JANITOR_INTERVAL: Duration = Duration::from_secs(15);
+
+struct Poller {
+    username: String,
+    receiver: Arc<tokio::sync::Mutex<UnboundedReceiver<Outbound>>>,
+    last_seen: Instant,
+}
+
+lazy_static! {
+    static ref POLLERS: Mutex<HashMap<Uuid, Poller>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Debug)]
+pub struct PollSession {
+    pub client_id: Uuid,
+}
+
+/// HTTP transports have no hello frame, the client picks its protocol
+/// version with `?version=` instead (the newest one by default).
+#[derive(Deserialize, Debug)]
+pub struct VersionQuery {
+    pub version: Option<u32>,
+    /// Same as on `/ws`, see `chat::WsQuery`.
+    pub device: Option<String>,
+}
+
+impl VersionQuery {
+    fn negotiate(&self) -> Option<u32> {
+        match self.version {
+            Some(version) => protocol::negotiate(&[version]),
+            None => SUPPORTED_VERSIONS.last().copied(),
+        }
+    }
+}
+
+fn frame_reply(frame: &ServerFrame, status: StatusCode) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(frame), status))
+}
+
+/// Unregisters an SSE client once warp drops its event stream, which is
+/// how we learn the connection has gone away.
+struct Registration {
+    clients: Clients,
+    db: Db,
+    client_id: Uuid,
+    username: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let clients = self.clients.clone();
+        let db = self.db.clone();
+        let client_id = self.client_id;
+        let username = std::mem::take(&mut self.username);
+        tokio::spawn(async move {
+            chat::unregister_client(&clients, &db, client_id, &username).await;
+        });
+    }
+}
+
+pub async fn handle_sse(
+    query: VersionQuery,
+    session_token: Option<String>,
+    clients: Clients,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let version = match query.negotiate() {
+        Some(version) => version,
+        None => return Ok(frame_reply(&protocol::unsupported_version(), StatusCode::BAD_REQUEST)),
+    };
+
+    let device = offline::device_id(query.device.as_deref());
+    let (client_id, rx) = chat::register_client(&clients, &db, &username, version, Encoding::Json, &device).await;
+    let registration = Registration {
+        clients,
+        db,
+        client_id,
+        username,
+    };
+
+    let events = UnboundedReceiverStream::new(rx).filter_map(move |outbound| {
+        let _keep_registered = &registration;
+        // Registered as JSON, so every frame is text.
+        let event = match outbound {
+            Outbound::Text(text) => Some(Ok::<_, Infallible>(Event::default().data(text))),
+            Outbound::Binary(_) => None,
+        };
+        futures::future::ready(event)
+    });
+    Ok(Box::new(warp::sse::reply(warp::sse::keep_alive().stream(events))))
+}
+
+pub async fn handle_send(
+    body: bytes::Bytes,
+    session_token: Option<String>,
+    clients: Clients,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let msg_text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => return Ok(json_message("Message must be UTF-8.", StatusCode::BAD_REQUEST)),
+    };
+
+    // The ack comes back as the response body rather than over the stream.
+    match chat::handle_text(&msg_text, &username, &clients, &db).await {
+        Some(ack @ ServerFrame::Ack {
+            status: AckStatus::Accepted,
+            ..
+        }) => Ok(frame_reply(&ack, StatusCode::ACCEPTED)),
+        Some(rejection) => Ok(frame_reply(&rejection, StatusCode::BAD_REQUEST)),
+        None => Ok(Box::new(StatusCode::ACCEPTED)),
+    }
+}
+
+pub async fn handle_poll_connect(
+    query: VersionQuery,
+    session_token: Option<String>,
+    clients: Clients,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let version = match query.negotiate() {
+        Some(version) => version,
+        None => return Ok(frame_reply(&protocol::unsupported_version(), StatusCode::BAD_REQUEST)),
+    };
+
+    let device = offline::device_id(query.device.as_deref());
+    let (client_id, rx) = chat::register_client(&clients, &db, &username, version, Encoding::Json, &device).await;
+    POLLERS.lock().unwrap().insert(
+        client_id,
+        Poller {
+            username,
+            receiver: Arc::new(tokio::sync::Mutex::new(rx)),
+            last_seen: Instant::now(),
+        },
+    );
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&PollSession { client_id }),
+        StatusCode::CREATED,
+    )))
+}
+
+/// Waits for at least one message (or the poll timeout) and returns every
+/// message queued for the client as a JSON array.
+pub async fn handle_poll(
+    client_id: Uuid,
+    session_token: Option<String>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let receiver = {
+        let mut pollers = POLLERS.lock().unwrap();
+        match pollers.get_mut(&client_id) {
+            Some(poller) if poller.username == username => {
+                poller.last_seen = Instant::now();
+                poller.receiver.clone()
+            }
+            _ => return Ok(json_message("Unknown poll session.", StatusCode::GONE)),
+        }
+    };
+
+    let mut messages = Vec::new();
+    {
+        let mut rx = receiver.lock().await;
+        if let Ok(Some(first)) = tokio::time::timeout(POLL_TIMEOUT, rx.recv()).await {
+            messages.push(first);
+            while let Ok(next) = rx.try_recv() {
+                messages.push(next);
+            }
+        }
+    }
+    // Registered as JSON, so every frame is text.
+    let messages: Vec<String> = messages
+        .into_iter()
+        .filter_map(|outbound| match outbound {
+            Outbound::Text(text) => Some(text),
+            Outbound::Binary(_) => None,
+        })
+        .collect();
+
+    if let Some(poller) = POLLERS.lock().unwrap().get_mut(&client_id) {
+        poller.last_seen = Instant::now();
+    }
+
+    // Every queued message is already a serialized JSON document.
+    let body = format!("[{}]", messages.join(","));
+    Ok(Box::new(warp::reply::with_header(
+        body,
+        "Content-Type",
+        "application/json",
+    )))
+}
+
+pub async fn handle_poll_disconnect(
+    client_id: Uuid,
+    session_token: Option<String>,
+    clients: Clients,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let removed = {
+        let mut pollers = POLLERS.lock().unwrap();
+        match pollers.get(&client_id) {
+            Some(poller) if poller.username == username => pollers.remove(&client_id),
+            _ => None,
+        }
+    };
+
+    match removed {
+        Some(poller) => {
+            chat::unregister_client(&clients, &db, client_id, &poller.username).await;
+            Ok(json_message("Disconnected.", StatusCode::OK))
+        }
+        None => Ok(json_message("Unknown poll session.", StatusCode::GONE)),
+    }
+}
+
+/// Periodically drops long-polling clients that stopped polling.
+pub fn spawn_poll_janitor(clients: Clients, db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(JANITOR_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let expired: Vec<(Uuid, String)> = {
+                let mut pollers = POLLERS.lock().unwrap();
+                let expired: Vec<Uuid> = pollers
+                    .iter()
+                    .filter(|(_, poller)| {
+                        // A locked receiver means a poll is waiting right now.
+                        poller.last_seen.elapsed() > POLL_IDLE_TIMEOUT
+                            && poller.receiver.try_lock().is_ok()
+                    })
+                    .map(|(id, _)| *id)
+                    .collect();
+                expired
+                    .into_iter()
+                    .filter_map(|id| pollers.remove(&id).map(|poller| (id, poller.username)))
+                    .collect()
+            };
+
+            for (client_id, username) in expired {
+                chat::unregister_client(&clients, &db, client_id, &username).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::chat::{self, Clients, LOBBY_ROOM};
+use crate::db::Db;
+use crate::handling_files::{self, TEMP_DIR};
+use crate::limits;
+use crate::metadata;
+use crate::scanning;
+use crate::protocol::{ErrorCode, ServerFrame};
+
+/// Size of every chunk but the last one.
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+/// Unfinished uploads nobody has touched for this long are dropped.
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const JANITOR_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Unfinished uploads one user may have at a time.
+const MAX_PENDING_UPLOADS: usize = 4;
+
+/// An upload in progress. Chunks are appended to `temp_path` in order, so
+/// `received` bytes are on disk and hashed.
+struct PendingUpload {
+    filename: String,
+    size: u64,
+    sha256: String,
+    received: u64,
+    hasher: Sha256,
+    temp_path: PathBuf,
+    strip_metadata: Option<bool>,
+    /// The server already has this content, so no chunks are expected and
+    /// there is no temp file.
+    known: bool,
+    last_activity: Instant,
+}
+
+impl PendingUpload {
+    fn next_chunk(&self) -> u64 {
+        self.received.div_ceil(CHUNK_SIZE)
+    }
+
+    fn progress(&self, upload_id: &str) -> ServerFrame {
+        ServerFrame::UploadProgress {
+            upload_id: upload_id.to_string(),
+            received: self.received,
+            size: self.size,
+        }
+    }
+}
+
+type UploadHandle = Arc<tokio::sync::Mutex<PendingUpload>>;
+
+struct UploadEntry {
+    uploader: String,
+    /// The declared size, reserved against the uploader's quota.
+    size: u64,
+    upload: UploadHandle,
+}
+
+lazy_static! {
+    static ref UPLOADS: Mutex<HashMap<String, UploadEntry>> = Mutex::new(HashMap::new());
+}
+
+fn find_upload(upload_id: &str, uploader: &str) -> Result<UploadHandle, (ErrorCode, String)> {
+    let uploads = UPLOADS.lock().unwrap();
+    match uploads.get(upload_id) {
+        Some(entry) if entry.uploader == uploader => Ok(entry.upload.clone()),
+        _ => Err((ErrorCode::InvalidRequest, format!("Unknown upload {}.", upload_id))),
+    }
+}
+
+async fn remove_upload(upload_id: &str) {
+    let removed = UPLOADS.lock().unwrap().remove(upload_id).map(|entry| entry.upload);
+    if let Some(upload) = removed {
+        let _ = fs::remove_file(&upload.lock().await.temp_path).await;
+    }
+}
+
+/// The file an `UploadInit` frame announces.
+pub struct NewUpload {
+    pub filename: String,
+    pub size: u64,
+    pub sha256: String,
+    pub strip_metadata: Option<bool>,
+}
+
+/// Starts an upload, or picks up an unfinished one after a reconnect when
+/// the client passes the `upload_id` it was given.
+pub async fn handle_init(
+    request_id: Option<String>,
+    upload_id: Option<String>,
+    file: NewUpload,
+    uploader: &str,
+    db: &Db,
+) -> Result<ServerFrame, (ErrorCode, String)> {
+    if let Some(upload_id) = upload_id {
+        let upload = find_upload(&upload_id, uploader)?;
+        let mut upload = upload.lock().await;
+        if upload.size != file.size || !upload.sha256.eq_ignore_ascii_case(&file.sha256) {
+            return Err((
+                ErrorCode::InvalidRequest,
+                format!("Upload {} was started for a different file.", upload_id),
+            ));
+        }
+        // Drop anything written past the last complete chunk.
+        if let Ok(file) = fs::OpenOptions::new().write(true).open(&upload.temp_path).await {
+            let _ = file.set_len(upload.received).await;
+        }
+        upload.last_activity = Instant::now();
+        return Ok(ServerFrame::UploadReady {
+            request_id,
+            upload_id,
+            chunk_size: CHUNK_SIZE,
+            next_chunk: upload.next_chunk(),
+        });
+    }
+
+    start_upload(request_id, file, uploader, db, Path::new(TEMP_DIR)).await
+}
+
+/// Starts a new upload, receiving into a file in `temp_dir`.
+async fn start_upload(
+    request_id: Option<String>,
+    file: NewUpload,
+    uploader: &str,
+    db: &Db,
+    temp_dir: &Path,
+) -> Result<ServerFrame, (ErrorCode, String)> {
+    let NewUpload {
+        filename,
+        size,
+        sha256,
+        strip_metadata,
+    } = file;
+    if filename.trim().is_empty() {
+        return Err((ErrorCode::InvalidRequest, "Filename cannot be empty.".into()));
+    }
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err((ErrorCode::InvalidRequest, "sha256 must be 64 hex digits.".into()));
+    }
+    if let Err(rejection) = limits::check_file_size(size) {
+        return Err((rejection.code(), rejection.to_string()));
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+    let temp_path = temp_dir.join(format!("{}.part", upload_id));
+    let sha256 = sha256.to_ascii_lowercase();
+    // Content that is already stored is not sent again: the upload starts
+    // out complete and the client goes straight to `UploadFinish`. Not if
+    // its metadata is to be removed, which would change the content, or if
+    // uploads are scanned, which needs the file.
+    let known = !scanning::enabled()
+        && handling_files::known_content(db, &sha256, size, LOBBY_ROOM)
+            .await
+            .filter(|mime| !metadata::applies(mime, strip_metadata))
+            .is_some();
+    if !known {
+        let created = async {
+            fs::create_dir_all(temp_dir).await?;
+            fs::File::create(&temp_path).await
+        };
+        if let Err(e) = created.await {
+            eprintln!("Failed to create temp file for upload {}: {}", upload_id, e);
+            return Err((ErrorCode::Internal, "Failed to start upload.".into()));
+        }
+    }
+
+    let upload = PendingUpload {
+        filename,
+        size,
+        sha256,
+        received: if known { size } else { 0 },
+        hasher: Sha256::new(),
+        temp_path,
+        strip_metadata,
+        known,
+        last_activity: Instant::now(),
+    };
+    let next_chunk = upload.next_chunk();
+    let temp_path = upload.temp_path.clone();
+    // The declared size stays reserved until the upload finishes or is
+    // dropped, so starting many uploads at once cannot get past the quota.
+    // It is added under the same lock the others are counted under, so
+    // concurrent inits see each other.
+    let pending = {
+        let mut uploads = UPLOADS.lock().unwrap();
+        let (count, bytes) = uploads
+            .values()
+            .filter(|entry| entry.uploader == uploader)
+            .fold((0, 0), |(count, bytes), entry| (count + 1, bytes + entry.size));
+        (count < MAX_PENDING_UPLOADS).then(|| {
+            uploads.insert(
+                upload_id.clone(),
+                UploadEntry {
+                    uploader: uploader.to_string(),
+                    size,
+                    upload: Arc::new(tokio::sync::Mutex::new(upload)),
+                },
+            );
+            bytes
+        })
+    };
+    let Some(pending) = pending else {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err((
+            ErrorCode::QuotaExceeded,
+            format!("You already have {} uploads in progress, finish one first.", MAX_PENDING_UPLOADS),
+        ));
+    };
+    // Checked again when the file is stored, this just saves sending it.
+    if let Err(rejection) = limits::check_size(db, uploader, size, pending).await {
+        remove_upload(&upload_id).await;
+        return Err((rejection.code(), rejection.to_string()));
+    }
+
+    Ok(ServerFrame::UploadReady {
+        request_id,
+        upload_id,
+        chunk_size: CHUNK_SIZE,
+        next_chunk,
+    })
+}
+
+/// Appends chunk `index` and replies with the upload's progress. Chunks that
+/// were already received (resent after a reconnect) are acknowledged again
+/// without being written.
+pub async fn handle_chunk(
+    upload_id: String,
+    index: u64,
+    data: Vec<u8>,
+    uploader: &str,
+) -> Result<ServerFrame, (ErrorCode, String)> {
+    let upload = find_upload(&upload_id, uploader)?;
+    let mut upload = upload.lock().await;
+    upload.last_activity = Instant::now();
+
+    let expected = upload.next_chunk();
+    if index < expected {
+        return Ok(upload.progress(&upload_id));
+    }
+    if index > expected {
+        return Err((
+            ErrorCode::InvalidRequest,
+            format!("Upload {}: expected chunk {}, got {}.", upload_id, expected, index),
+        ));
+    }
+    let expected_len = CHUNK_SIZE.min(upload.size - upload.received);
+    if data.len() as u64 != expected_len {
+        return Err((
+            ErrorCode::InvalidRequest,
+            format!("Upload {}: chunk {} must be {} bytes.", upload_id, index, expected_len),
+        ));
+    }
+
+    // The first chunk is enough to tell the file type.
+    if index == 0 {
+        if let Err(rejection) = limits::check_type(&data) {
+            drop(upload);
+            remove_upload(&upload_id).await;
+            return Err((rejection.code(), format!("Upload {}: {}", upload_id, rejection)));
+        }
+    }
+
+    let written = async {
+        let mut file = fs::OpenOptions::new().append(true).open(&upload.temp_path).await?;
+        file.write_all(&data).await?;
+        file.flush().await
+    };
+    if let Err(e) = written.await {
+        eprintln!("Failed to write chunk {} of upload {}: {}", index, upload_id, e);
+        return Err((ErrorCode::Internal, format!("Upload {}: failed to save chunk.", upload_id)));
+    }
+
+    upload.hasher.update(&data);
+    upload.received += data.len() as u64;
+    Ok(upload.progress(&upload_id))
+}
+
+/// Checks that every byte arrived with the announced hash, then stores and
+/// announces the file like any other.
+pub async fn handle_finish(
+    upload_id: String,
+    uploader: &str,
+    clients: &Clients,
+    db: &Db,
+) -> Result<Option<i64>, (ErrorCode, String)> {
+    let upload = find_upload(&upload_id, uploader)?;
+    let upload = upload.lock().await;
+
+    if upload.received != upload.size {
+        return Err((
+            ErrorCode::InvalidRequest,
+            format!("Upload {} has {} of {} bytes.", upload_id, upload.received, upload.size),
+        ));
+    }
+    if upload.known {
+        UPLOADS.lock().unwrap().remove(&upload_id);
+        let file_id = match handling_files::store_known_file(db, uploader, &upload.filename, &upload.sha256, upload.size).await {
+            Ok(Some(file_id)) => file_id,
+            Ok(None) => {
+                return Err((
+                    ErrorCode::InvalidRequest,
+                    format!("Upload {}: the file is no longer stored, send it again.", upload_id),
+                ))
+            }
+            Err(e) => {
+                eprintln!("Failed to store upload {}: {}", upload_id, e);
+                return Err(e.to_frame_error());
+            }
+        };
+        return Ok(chat::publish_file(clients, db, uploader, upload.filename.clone(), file_id).await);
+    }
+
+    let digest = hex::encode(upload.hasher.clone().finalize());
+    if digest != upload.sha256 {
+        drop(upload);
+        remove_upload(&upload_id).await;
+        return Err((
+            ErrorCode::InvalidRequest,
+            format!("Upload {} does not match its sha256, start again.", upload_id),
+        ));
+    }
+
+    // Claim the upload so a second finish cannot store it twice.
+    UPLOADS.lock().unwrap().remove(&upload_id);
+    let file_id = match handling_files::store_file(db, uploader, &upload.filename, &upload.temp_path, &upload.sha256, upload.strip_metadata).await {
+        Ok(file_id) => file_id,
+        Err(e) => {
+            eprintln!("Failed to store upload {}: {}", upload_id, e);
+            chat::notify_quarantined(clients, uploader, &upload.filename, &e);
+            let _ = fs::remove_file(&upload.temp_path).await;
+            return Err(e.to_frame_error());
+        }
+    };
+
+    Ok(chat::publish_file(clients, db, uploader, upload.filename.clone(), file_id).await)
+}
+
+/// Periodically deletes uploads that were abandoned half way.
+pub fn spawn_upload_janitor() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(JANITOR_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let expired: Vec<String> = UPLOADS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, entry)| {
+                    // A locked upload is being written to right now.
+                    entry
+                        .upload
+                        .try_lock()
+                        .is_ok_and(|u| u.last_activity.elapsed() > UPLOAD_IDLE_TIMEOUT)
+                })
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            for upload_id in expired {
+                println!("Dropping abandoned upload {}", upload_id);
+                remove_upload(&upload_id).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CONFIG;
+
+    fn new_upload(size: u64) -> NewUpload {
+        NewUpload {
+            filename: "big.bin".into(),
+            size,
+            sha256: Uuid::new_v4().simple().to_string().repeat(2),
+            strip_metadata: None,
+        }
+    }
+
+    async fn init(uploader: &str, size: u64, db: &Db, temp_dir: &Path) -> Result<String, ErrorCode> {
+        match start_upload(None, new_upload(size), uploader, db, temp_dir).await {
+            Ok(ServerFrame::UploadReady { upload_id, .. }) => Ok(upload_id),
+            Ok(frame) => panic!("unexpected reply {:?}", frame),
+            Err((code, _)) => Err(code),
+        }
+    }
+
+    fn part_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "part"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn pending_uploads_are_capped() {
+        let (dir, db) = crate::db::open_temp().await;
+        let mut started = Vec::new();
+        for _ in 0..MAX_PENDING_UPLOADS {
+            started.push(init("capped", 1, &db, dir.path()).await.expect("upload starts"));
+        }
+        assert_eq!(init("capped", 1, &db, dir.path()).await, Err(ErrorCode::QuotaExceeded));
+        // The refused upload's file is gone again.
+        assert_eq!(part_files(dir.path()), MAX_PENDING_UPLOADS);
+        // Other users are not affected.
+        let other = init("uncapped", 1, &db, dir.path()).await.expect("upload starts");
+
+        remove_upload(&started.pop().unwrap()).await;
+        started.push(init("capped", 1, &db, dir.path()).await.expect("a slot was freed"));
+        for upload_id in started.iter().chain([&other]) {
+            remove_upload(upload_id).await;
+        }
+        assert_eq!(part_files(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn pending_uploads_reserve_quota() {
+        let (dir, db) = crate::db::open_temp().await;
+        let size = CONFIG.max_file_size.min(CONFIG.user_quota);
+        // Leave room for exactly one more file.
+        let stored = CONFIG.user_quota - size;
+        db.run(move |conn| {
+            conn.execute(
+                "INSERT INTO storage_usage (username, bytes) VALUES ('reserver', ?1)",
+                rusqlite::params![stored],
+            )
+        })
+        .await
+        .unwrap();
+
+        let first = init("reserver", size, &db, dir.path()).await.expect("fits the quota");
+        assert_eq!(init("reserver", size, &db, dir.path()).await, Err(ErrorCode::QuotaExceeded));
+        assert_eq!(UPLOADS.lock().unwrap().values().filter(|e| e.uploader == "reserver").count(), 1);
+
+        // Dropping the upload gives the reservation back.
+        remove_upload(&first).await;
+        let second = init("reserver", size, &db, dir.path()).await.expect("fits again");
+        remove_upload(&second).await;
+    }
+}
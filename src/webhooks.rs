@@ -0,0 +1,636 @@
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rand::RngCore;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use crate::auth::{json_message, session_user};
+use crate::chat;
+use crate::config::CONFIG;
+use crate::db::{self, Db};
+use crate::protocol::ServerFrame;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build webhook HTTP client");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    Message,
+    File,
+    Join,
+    Leave,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Message => "message",
+            WebhookEvent::File => "file",
+            WebhookEvent::Join => "join",
+            WebhookEvent::Leave => "leave",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateWebhook {
+    pub url: String,
+    pub secret: Option<String>,
+    pub room: Option<String>,
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WebhookInfo {
+    pub id: i64,
+    pub url: String,
+    pub room: Option<String>,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatedWebhook {
+    pub id: i64,
+    pub secret: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeliveryInfo {
+    pub delivery_id: String,
+    pub event: String,
+    pub attempt: u32,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct WebhookPayload<'a> {
+    delivery_id: &'a str,
+    event: WebhookEvent,
+    room: &'a str,
+    timestamp: i64,
+    username: &'a str,
+    message: Option<&'a ServerFrame>,
+}
+
+#[derive(Debug, Clone)]
+struct Webhook {
+    id: i64,
+    url: String,
+    secret: String,
+}
+
+fn parse_events(events: &str) -> Vec<WebhookEvent> {
+    events
+        .split(',')
+        .filter_map(|e| match e {
+            "message" => Some(WebhookEvent::Message),
+            "file" => Some(WebhookEvent::File),
+            "join" => Some(WebhookEvent::Join),
+            "leave" => Some(WebhookEvent::Leave),
+            _ => None,
+        })
+        .collect()
+}
+
+fn join_events(events: &[WebhookEvent]) -> String {
+    events.iter().map(|e| e.as_str()).collect::<Vec<_>>().join(",")
+}
+
+/// Signs `"{timestamp}.{body}"` with HMAC-SHA256 so receivers can reject
+/// replayed or tampered payloads.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn load_webhooks(db: &Db, room: &str, event: WebhookEvent) -> rusqlite::Result<Vec<Webhook>> {
+    let room = room.to_string();
+    db.run(move |conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id, url, secret, events FROM webhooks WHERE room IS NULL OR room = ?1",
+        )?;
+        let rows = stmt.query_map(params![room], |row| {
+            let events: String = row.get(3)?;
+            Ok((
+                Webhook {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    secret: row.get(2)?,
+                },
+                events,
+            ))
+        })?;
+
+        let mut hooks = Vec::new();
+        for row in rows {
+            let (hook, events) = row?;
+            if events.is_empty() || parse_events(&events).contains(&event) {
+                hooks.push(hook);
+            }
+        }
+        Ok(hooks)
+    })
+    .await
+}
+
+/// Queues a delivery of `event` to every webhook subscribed to it, either
+/// globally or for `room`. Deliveries run in the background.
+pub async fn dispatch(
+    db: &Db,
+    room: &str,
+    event: WebhookEvent,
+    username: &str,
+    message: Option<&ServerFrame>,
+) {
+    let hooks = match load_webhooks(db, room, event).await {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            error!("Failed to load webhooks: {:?}", e);
+            return;
+        }
+    };
+
+    for hook in hooks {
+        let delivery_id = Uuid::new_v4().to_string();
+        let payload = WebhookPayload {
+            delivery_id: &delivery_id,
+            event,
+            room,
+            timestamp: db::now(),
+            username,
+            message,
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize webhook payload: {:?}", e);
+                return;
+            }
+        };
+        tokio::spawn(deliver(db.clone(), hook, event, delivery_id, body));
+    }
+}
+
+async fn deliver(db: Db, hook: Webhook, event: WebhookEvent, delivery_id: String, body: String) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let timestamp = db::now();
+        let signature = sign(&hook.secret, timestamp, &body);
+
+        let result = HTTP_CLIENT
+            .post(&hook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Event", event.as_str())
+            .header("X-Webhook-Delivery", &delivery_id)
+            .header("X-Webhook-Timestamp", timestamp.to_string())
+            .header("X-Webhook-Signature", format!("sha256={}", signature))
+            .body(body.clone())
+            .send()
+            .await;
+
+        let (status_code, error, retryable) = match result {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_success() {
+                    (Some(status.as_u16()), None, false)
+                } else {
+                    // Client errors other than timeouts and rate limiting
+                    // will not go away by sending the same payload again.
+                    let retryable = status.is_server_error()
+                        || status.as_u16() == StatusCode::REQUEST_TIMEOUT.as_u16()
+                        || status.as_u16() == StatusCode::TOO_MANY_REQUESTS.as_u16();
+                    (Some(status.as_u16()), Some(format!("HTTP {}", status)), retryable)
+                }
+            }
+            Err(e) => (None, Some(e.to_string()), true),
+        };
+
+        if let Err(e) = record_delivery(
+            &db,
+            hook.id,
+            &delivery_id,
+            event,
+            attempt,
+            status_code,
+            error.clone(),
+        )
+        .await
+        {
+            error!("Failed to record webhook delivery: {:?}", e);
+        }
+
+        match error {
+            None => {
+                info!("Delivered {} webhook {} to {}", event.as_str(), delivery_id, hook.url);
+                return;
+            }
+            Some(e) if retryable && attempt < MAX_ATTEMPTS => {
+                warn!(
+                    "Webhook {} attempt {} to {} failed: {}, retrying in {:?}",
+                    delivery_id, attempt, hook.url, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Some(e) => {
+                warn!(
+                    "Giving up on webhook {} to {} after {} attempt(s): {}",
+                    delivery_id, hook.url, attempt, e
+                );
+                return;
+            }
+        }
+    }
+}
+
+async fn record_delivery(
+    db: &Db,
+    webhook_id: i64,
+    delivery_id: &str,
+    event: WebhookEvent,
+    attempt: u32,
+    status_code: Option<u16>,
+    error: Option<String>,
+) -> rusqlite::Result<()> {
+    let delivery_id = delivery_id.to_string();
+    db.run(move |conn| {
+        conn.execute(
+            "INSERT INTO webhook_deliveries
+                (webhook_id, delivery_id, event, attempt, status_code, error, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![webhook_id, delivery_id, event.as_str(), attempt, status_code, error, db::now()],
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn handle_create_webhook(
+    request: CreateWebhook,
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+    // The server makes the requests and reports back how they went, so a
+    // webhook could probe hosts only the server can reach.
+    if !CONFIG.moderators.contains(&username) {
+        return Ok(json_message("Only moderators can create webhooks.", StatusCode::FORBIDDEN));
+    }
+
+    match reqwest::Url::parse(&request.url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => return Ok(json_message("Webhook URL must be an http(s) URL.", StatusCode::BAD_REQUEST)),
+    }
+
+    if let Some(room) = &request.room {
+        if !chat::room_exists(room) {
+            return Ok(json_message("Unknown room.", StatusCode::BAD_REQUEST));
+        }
+    }
+
+    let secret = match request.secret {
+        Some(secret) if !secret.is_empty() => secret,
+        _ => {
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            hex::encode(bytes)
+        }
+    };
+
+    let (url, key, creator) = (request.url.clone(), secret.clone(), username.clone());
+    let result = db
+        .run(move |conn| {
+            conn.execute(
+                "INSERT INTO webhooks (url, secret, room, events, created_by, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    url,
+                    key,
+                    request.room,
+                    join_events(&request.events),
+                    creator,
+                    db::now()
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await;
+
+    match result {
+        Ok(id) => {
+            info!("User '{}' created webhook {} for {}", username, id, request.url);
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&CreatedWebhook { id, secret }),
+                StatusCode::CREATED,
+            )))
+        }
+        Err(e) => {
+            error!("Failed to create webhook: {:?}", e);
+            Ok(json_message("Failed to create webhook.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+pub async fn handle_list_webhooks(
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let result = db
+        .run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, url, room, events, created_at FROM webhooks
+                 WHERE created_by = ?1 ORDER BY id",
+            )?;
+            let hooks = stmt.query_map(params![username], |row| {
+                let events: String = row.get(3)?;
+                Ok(WebhookInfo {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    room: row.get(2)?,
+                    events: parse_events(&events),
+                    created_at: row.get(4)?,
+                })
+            })?;
+            hooks.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await;
+
+    match result {
+        Ok(hooks) => Ok(Box::new(warp::reply::json(&hooks))),
+        Err(e) => {
+            error!("Failed to list webhooks: {:?}", e);
+            Ok(json_message("Failed to list webhooks.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+pub async fn handle_delete_webhook(
+    id: i64,
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let result = db
+        .run(move |conn| {
+            let tx = conn.transaction()?;
+            // Deliveries first, they refer to the webhook.
+            tx.execute(
+                "DELETE FROM webhook_deliveries WHERE webhook_id IN
+                     (SELECT id FROM webhooks WHERE id = ?1 AND created_by = ?2)",
+                params![id, username],
+            )?;
+            let deleted = tx.execute(
+                "DELETE FROM webhooks WHERE id = ?1 AND created_by = ?2",
+                params![id, username],
+            )?;
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await;
+
+    match result {
+        Ok(0) => Ok(json_message("Webhook not found.", StatusCode::NOT_FOUND)),
+        Ok(_) => Ok(json_message("Webhook deleted.", StatusCode::OK)),
+        Err(e) => {
+            error!("Failed to delete webhook {}: {:?}", id, e);
+            Ok(json_message("Failed to delete webhook.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+pub async fn handle_list_deliveries(
+    id: i64,
+    session_token: Option<String>,
+    db: Db,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let username = match session_user(session_token.as_deref()) {
+        Some(username) => username,
+        None => return Ok(json_message("Not logged in.", StatusCode::UNAUTHORIZED)),
+    };
+
+    let result = db
+        .run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT d.delivery_id, d.event, d.attempt, d.status_code, d.error, d.created_at
+                 FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+                 WHERE w.id = ?1 AND w.created_by = ?2
+                 ORDER BY d.id DESC LIMIT 100",
+            )?;
+            let deliveries = stmt.query_map(params![id, username], |row| {
+                Ok(DeliveryInfo {
+                    delivery_id: row.get(0)?,
+                    event: row.get(1)?,
+                    attempt: row.get(2)?,
+                    status_code: row.get(3)?,
+                    error: row.get(4)?,
+                    created_at: row.get(5)?,
+                })
+            })?;
+            deliveries.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await;
+
+    match result {
+        Ok(deliveries) => Ok(Box::new(warp::reply::json(&deliveries))),
+        Err(e) => {
+            error!("Failed to list deliveries for webhook {}: {:?}", id, e);
+            Ok(json_message("Failed to list deliveries.", StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use warp::http::HeaderMap;
+    use warp::{Filter, Reply};
+
+    struct Received {
+        headers: HeaderMap,
+        body: String,
+        at: Instant,
+    }
+
+    /// A local stand-in for a receiver, answering with `statuses` in turn
+    /// and repeating the last one.
+    fn receiver(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let route = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers, body: bytes::Bytes| {
+                let mut log = log.lock().unwrap();
+                log.push(Received {
+                    headers,
+                    body: String::from_utf8(body.to_vec()).unwrap(),
+                    at: Instant::now(),
+                });
+                let status = statuses[(log.len() - 1).min(statuses.len() - 1)];
+                warp::reply::with_status("", StatusCode::from_u16(status).unwrap())
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hook", address), received)
+    }
+
+    async fn add_webhook(db: &Db, url: &str) -> Webhook {
+        let stored = url.to_string();
+        let id = db
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT INTO webhooks (url, secret, room, events, created_by, created_at)
+                     VALUES (?1, 'secret', NULL, '', 'alice', 0)",
+                    params![stored],
+                )?;
+                Ok(conn.last_insert_rowid())
+            })
+            .await
+            .unwrap();
+        Webhook {
+            id,
+            url: url.to_string(),
+            secret: "secret".into(),
+        }
+    }
+
+    async fn deliveries(db: &Db, webhook_id: i64) -> Vec<(String, u32, Option<u16>, Option<String>)> {
+        db.run(move |conn| {
+            conn.prepare(
+                "SELECT delivery_id, attempt, status_code, error FROM webhook_deliveries
+                 WHERE webhook_id = ?1 ORDER BY id",
+            )?
+            .query_map(params![webhook_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    fn header<'a>(request: &'a Received, name: &str) -> &'a str {
+        request.headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn signs_deliveries() {
+        let (_dir, db) = db::open_temp().await;
+        let (url, received) = receiver(vec![200]);
+        let hook = add_webhook(&db, &url).await;
+
+        let body = r#"{"event":"message"}"#.to_string();
+        deliver(db.clone(), hook.clone(), WebhookEvent::Message, "d1".into(), body.clone()).await;
+
+        let received = std::mem::take(&mut *received.lock().unwrap());
+        assert_eq!(received.len(), 1);
+        let request = &received[0];
+        assert_eq!(request.body, body);
+        assert_eq!(header(request, "content-type"), "application/json");
+        assert_eq!(header(request, "x-webhook-event"), "message");
+        assert_eq!(header(request, "x-webhook-delivery"), "d1");
+        let timestamp: i64 = header(request, "x-webhook-timestamp").parse().unwrap();
+        assert!((db::now() - timestamp).abs() < 60);
+
+        let signature = header(request, "x-webhook-signature").strip_prefix("sha256=").unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(format!("{}.{}", timestamp, body).as_bytes());
+        mac.verify_slice(&hex::decode(signature).unwrap()).expect("valid signature");
+
+        assert_eq!(deliveries(&db, hook.id).await, vec![("d1".to_string(), 1, Some(200), None)]);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_backoff() {
+        let (_dir, db) = db::open_temp().await;
+        let (url, received) = receiver(vec![500, 503, 200]);
+        let hook = add_webhook(&db, &url).await;
+
+        deliver(db.clone(), hook.clone(), WebhookEvent::Join, "d2".into(), "{}".into()).await;
+
+        let received = std::mem::take(&mut *received.lock().unwrap());
+        assert_eq!(received.len(), 3);
+        assert!(received[1].at - received[0].at >= INITIAL_BACKOFF);
+        assert!(received[2].at - received[1].at >= INITIAL_BACKOFF * 2);
+        // Every attempt is the same delivery.
+        assert!(received.iter().all(|request| header(request, "x-webhook-delivery") == "d2"));
+
+        let rows = deliveries(&db, hook.id).await;
+        let attempts: Vec<_> = rows.iter().map(|(_, attempt, status, _)| (*attempt, *status)).collect();
+        assert_eq!(attempts, vec![(1, Some(500)), (2, Some(503)), (3, Some(200))]);
+        assert!(rows[0].3.as_deref().unwrap().starts_with("HTTP 500"));
+        assert_eq!(rows[2].3, None);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (_dir, db) = db::open_temp().await;
+        let (url, received) = receiver(vec![404, 200]);
+        let hook = add_webhook(&db, &url).await;
+
+        deliver(db.clone(), hook.clone(), WebhookEvent::Leave, "d3".into(), "{}".into()).await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        let rows = deliveries(&db, hook.id).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].1, rows[0].2), (1, Some(404)));
+    }
+
+    #[tokio::test]
+    async fn only_moderators_create_webhooks() {
+        let (_dir, db) = db::open_temp().await;
+        let token = Uuid::new_v4().to_string();
+        crate::shared::SESSIONS.lock().unwrap().insert(token.clone(), "mallory".into());
+
+        let request = CreateWebhook {
+            url: "http://127.0.0.1:22/".into(),
+            secret: None,
+            room: None,
+            events: Vec::new(),
+        };
+        let reply = handle_create_webhook(request, Some(token), db.clone()).await.unwrap();
+        assert_eq!(reply.into_response().status(), StatusCode::FORBIDDEN);
+
+        let count: i64 = db
+            .run(|conn| conn.query_row("SELECT COUNT(*) FROM webhooks", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
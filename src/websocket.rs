@@ -0,0 +1,359 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use futures::{future, FutureExt};
+use sha1::{Digest, Sha1};
+use soketto::connection::{Builder, Error, Mode};
+use soketto::extension::Extension;
+use soketto::{Data, Incoming};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use warp::http::{header, HeaderMap, StatusCode};
+use warp::hyper::server::conn::AddrStream;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::upgrade::{OnUpgrade, Upgraded};
+use warp::hyper::{self, Body, Request};
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+use crate::compression;
+use crate::config::CONFIG;
+use crate::protocol::Outbound;
+
+// WebSocket upgrades, done here instead of with `warp::ws` so connections
+// can negotiate permessage-deflate (see `compression`), which warp's
+// tungstenite sockets do not support. warp keeps hyper's upgrade handle to
+// itself, so `serve` moves it somewhere the `ws` filter can reach it.
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Same limits as `warp::ws`. `/send` takes messages of the same size.
+pub const MAX_MESSAGE_SIZE: usize = 64 << 20;
+const MAX_FRAME_SIZE: usize = 16 << 20;
+
+pub type Sender = soketto::Sender<Compat<Upgraded>>;
+pub type Receiver = soketto::Receiver<Compat<Upgraded>>;
+
+/// hyper's handle on a connection that asked for an upgrade. It cannot be
+/// cloned, which request extensions need to be for filters to read them.
+#[derive(Clone)]
+struct PendingUpgrade(Arc<Mutex<Option<OnUpgrade>>>);
+
+#[derive(Debug)]
+pub struct MissingConnectionUpgrade;
+
+impl warp::reject::Reject for MissingConnectionUpgrade {}
+
+/// Serves `service` like `warp::serve(..).bind_ephemeral(address)`, but
+/// leaves upgrades to the `ws` filter.
+pub fn serve<S>(service: S, address: SocketAddr) -> (SocketAddr, impl Future<Output = ()>)
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let make_service = make_service_fn(move |_: &AddrStream| {
+        let mut service = service.clone();
+        future::ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+            if request.headers().contains_key(header::UPGRADE) {
+                let upgrade = hyper::upgrade::on(&mut request);
+                request.extensions_mut().insert(PendingUpgrade(Arc::new(Mutex::new(Some(upgrade)))));
+            }
+            // Boxed, or proving warp's deeply nested future fits the
+            // server's bounds overflows the compiler's recursion limit.
+            service.call(request).boxed()
+        }))
+    });
+    let server = hyper::Server::bind(&address).serve(make_service);
+    let address = server.local_addr();
+    let server = server.map(|result| {
+        if let Err(e) = result {
+            eprintln!("Server error: {}", e);
+        }
+    });
+    (address, server)
+}
+
+/// Matches a WebSocket upgrade request, checking the same headers as
+/// `warp::ws()`.
+pub fn ws() -> impl Filter<Extract = (Ws,), Error = Rejection> + Clone {
+    let connection_has_upgrade = warp::header::<String>("connection")
+        .and_then(|connection: String| async move {
+            if connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(MissingConnectionUpgrade))
+            }
+        })
+        .untuple_one();
+
+    warp::get()
+        .and(connection_has_upgrade)
+        .and(warp::header::exact_ignore_case("upgrade", "websocket"))
+        .and(warp::header::exact("sec-websocket-version", "13"))
+        .and(warp::header::<String>("sec-websocket-key"))
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<PendingUpgrade>())
+        .map(|key: String, headers: HeaderMap, upgrade: Option<PendingUpgrade>| {
+            // Offers may be spread over several header lines.
+            let offers = headers
+                .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            Ws {
+                key,
+                offers,
+                upgrade: upgrade.and_then(|upgrade| upgrade.0.lock().unwrap().take()),
+            }
+        })
+}
+
+/// Extracted by the `ws` filter, and used to finish an upgrade.
+pub struct Ws {
+    key: String,
+    /// The client's `Sec-WebSocket-Extensions`.
+    offers: String,
+    upgrade: Option<OnUpgrade>,
+}
+
+impl Ws {
+    /// Switches protocols, negotiating compression if the client offered it
+    /// and the server allows it, then runs `func` on the connection.
+    pub fn on_upgrade<F, U>(self, func: F) -> Response
+    where
+        F: FnOnce(Sender, Receiver) -> U + Send + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        let deflate = if CONFIG.ws_compression {
+            compression::negotiate(&self.offers, CONFIG.ws_compression_threshold, CONFIG.ws_compression_level)
+        } else {
+            None
+        };
+
+        let mut response = warp::http::Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_ACCEPT, accept_key(&self.key));
+        if let Some((_, extension)) = &deflate {
+            response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, extension);
+        }
+
+        match self.upgrade {
+            Some(upgrade) => {
+                tokio::spawn(async move {
+                    let upgraded = match upgrade.await {
+                        Ok(upgraded) => upgraded,
+                        Err(e) => {
+                            eprintln!("WebSocket upgrade failed: {}", e);
+                            return;
+                        }
+                    };
+                    let mut builder = Builder::new(upgraded.compat(), Mode::Server);
+                    builder.set_max_message_size(MAX_MESSAGE_SIZE);
+                    builder.set_max_frame_size(MAX_FRAME_SIZE);
+                    if let Some((extension, _)) = deflate {
+                        builder.add_extensions([Box::new(extension) as Box<dyn Extension + Send>]);
+                    }
+                    let (sender, receiver) = builder.finish();
+                    func(sender, receiver).await;
+                });
+            }
+            None => eprintln!("WebSocket upgrade requested on a connection that cannot be upgraded"),
+        }
+
+        response.body(Body::empty()).expect("valid headers")
+    }
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Waits for the next text or binary message, answering pings on the way.
+/// Returns `None` once the client has closed the connection.
+pub async fn receive(receiver: &mut Receiver) -> Result<Option<Message>, Error> {
+    let mut data = Vec::new();
+    loop {
+        match receiver.receive(&mut data).await {
+            Ok(Incoming::Data(Data::Text(_))) => {
+                return String::from_utf8(data)
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|e| Error::Utf8(e.utf8_error()));
+            }
+            Ok(Incoming::Data(Data::Binary(_))) => return Ok(Some(Message::Binary(data))),
+            Ok(Incoming::Pong(_)) => continue,
+            Ok(Incoming::Closed(_)) | Err(Error::Closed) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends one frame as a text or binary message.
+pub async fn send(sender: &mut Sender, outbound: Outbound) -> Result<(), Error> {
+    match outbound {
+        Outbound::Text(text) => sender.send_text_owned(text).await?,
+        Outbound::Binary(data) => sender.send_binary_mut(data).await?,
+    }
+    sender.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+    /// Echoes every message back.
+    async fn echo_server() -> SocketAddr {
+        let route = ws().map(|ws: Ws| {
+            ws.on_upgrade(|mut sender, mut receiver| async move {
+                while let Ok(Some(message)) = receive(&mut receiver).await {
+                    let outbound = match message {
+                        Message::Text(text) => Outbound::Text(text),
+                        Message::Binary(data) => Outbound::Binary(data),
+                    };
+                    if send(&mut sender, outbound).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+        let (address, server) = serve(warp::service(route), SocketAddr::from(([127, 0, 0, 1], 0)));
+        tokio::spawn(server);
+        address
+    }
+
+    /// Upgrades a raw connection, returning the response head in lowercase.
+    async fn upgrade(address: SocketAddr, extensions: Option<&str>) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut request = "GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Upgrade\r\n\
+                           Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+            .to_string();
+        if let Some(extensions) = extensions {
+            request.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", extensions));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        (stream, String::from_utf8(head).unwrap().to_lowercase())
+    }
+
+    /// A single masked text frame, as clients send them.
+    fn client_frame(rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x81 | if rsv1 { 0x40 } else { 0 }];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /// Reads one unmasked frame, returning its first byte and payload.
+    async fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let first = stream.read_u8().await.unwrap();
+        let len = match stream.read_u8().await.unwrap() {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        (first, payload)
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.flush().unwrap();
+        let compressed = encoder.get_ref();
+        compressed[..compressed.len() - TRAILER.len()].to_vec()
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&TRAILER);
+        let mut inflated = Vec::new();
+        let _ = DeflateDecoder::new(&input[..]).read_to_end(&mut inflated);
+        inflated
+    }
+
+    fn large_message() -> Vec<u8> {
+        b"{\"type\":\"message\",\"sender\":\"alice\",\"content\":\"see you in five\"}".repeat(20)
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // The example from RFC 6455, section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn negotiates_permessage_deflate() {
+        let address = echo_server().await;
+        let (mut stream, head) = upgrade(address, Some("permessage-deflate; client_max_window_bits")).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="), "{}", head);
+        assert!(head.contains("sec-websocket-extensions: permessage-deflate\r\n"), "{}", head);
+
+        // A compressed message is inflated, and echoed compressed.
+        let message = large_message();
+        stream.write_all(&client_frame(true, &deflate(&message))).await.unwrap();
+        let (first, payload) = read_frame(&mut stream).await;
+        assert_eq!(first, 0xc1, "FIN, RSV1 and text");
+        assert!(payload.len() < message.len() / 4);
+        assert_eq!(inflate(&payload), message);
+
+        // Short messages stay uncompressed in both directions.
+        stream.write_all(&client_frame(false, b"hi")).await.unwrap();
+        assert_eq!(read_frame(&mut stream).await, (0x81, b"hi".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn works_without_compression() {
+        let address = echo_server().await;
+        let (mut stream, head) = upgrade(address, None).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(!head.contains("sec-websocket-extensions"), "{}", head);
+
+        let message = large_message();
+        stream.write_all(&client_frame(false, &message)).await.unwrap();
+        assert_eq!(read_frame(&mut stream).await, (0x81, message));
+
+        // RSV1 means nothing on this connection.
+        stream.write_all(&client_frame(true, &deflate(b"hi"))).await.unwrap();
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest).await;
+        assert!(!rest.starts_with(&[0x81]), "{:?}", rest);
+    }
+}
@@ -0,0 +1,43 @@
+//! Request bodies the server refuses to read.
+
+mod common;
+
+use common::Server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends only the head of a request announcing `content_length` bytes, and
+/// returns the status line of the reply.
+async fn announce(server: &Server, path: &str, cookie: &str, content_length: u64) -> String {
+    let mut stream = TcpStream::connect(server.url.trim_start_matches("http://")).await.unwrap();
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\nContent-Length: {}\r\n\r\n",
+        path, cookie, content_length
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    let mut reply = vec![0; 64];
+    let read = stream.read(&mut reply).await.unwrap();
+    String::from_utf8_lossy(&reply[..read]).lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn send_is_capped_like_websocket_messages() {
+    let dir = tempfile::tempdir().unwrap();
+    let client = reqwest::Client::new();
+    let server = Server::start(dir.path(), &[]);
+    common::register(&client, &server, "alice").await;
+    let cookie = common::login(&client, &server, "alice").await;
+
+    let status = announce(&server, "/send", &cookie, (64 << 20) + 1).await;
+    assert!(status.starts_with("HTTP/1.1 413"), "{}", status);
+
+    let response = client
+        .post(format!("{}/send", server.url))
+        .header("cookie", &cookie)
+        .body(r#"{"type":"message","content":"hello"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    server.stop();
+}
@@ -0,0 +1,134 @@
+//! Runs the server binary in a temporary directory, for tests that need the
+//! whole server: its startup, or several parts working together.
+
+// Each test binary uses only some of the helpers.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+pub struct Server {
+    child: Child,
+    pub url: String,
+    output: mpsc::Receiver<String>,
+}
+
+impl Server {
+    /// Starts the server in `dir` on a free port, with `CHAT_*` settings
+    /// from `env`, and waits until it listens.
+    pub fn start(dir: &Path, env: &[(&str, &str)]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_websockets2"))
+            .current_dir(dir)
+            .env("CHAT_ADDRESS", "127.0.0.1:0")
+            .env("CHAT_STORAGE", "local")
+            .env("CHAT_SCANNER", "none")
+            .envs(env.iter().copied())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("start server");
+        let (sender, output) = mpsc::channel();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        std::thread::spawn(move || {
+            for line in stdout.lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut server = Server {
+            child,
+            url: String::new(),
+            output,
+        };
+        let line = server.wait_for("Server running at ");
+        server.url = line["Server running at ".len()..].trim_end_matches('/').to_string();
+        server
+    }
+
+    /// Waits for a line starting with `prefix` and returns it. Indexing any
+    /// file on the way fails the test: the server only ever finds its own.
+    pub fn wait_for(&self, prefix: &str) -> String {
+        loop {
+            let line = self
+                .output
+                .recv_timeout(Duration::from_secs(30))
+                .unwrap_or_else(|_| panic!("server never printed {:?}", prefix));
+            assert!(!line.starts_with("Indexed"), "indexed its own files: {}", line);
+            if line.starts_with(prefix) {
+                return line;
+            }
+        }
+    }
+
+    pub fn stop(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+    }
+}
+
+fn credentials(username: &str) -> Value {
+    json!({ "username": username, "password": "secret" })
+}
+
+pub async fn register(client: &reqwest::Client, server: &Server, username: &str) {
+    let response = client
+        .post(format!("{}/register", server.url))
+        .json(&credentials(username))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "could not register {}", username);
+}
+
+/// Logs in and returns the session cookie to send along.
+pub async fn login(client: &reqwest::Client, server: &Server, username: &str) -> String {
+    let response = client
+        .post(format!("{}/login", server.url))
+        .json(&credentials(username))
+        .send()
+        .await
+        .unwrap();
+    let cookie = response.headers()["set-cookie"].to_str().unwrap();
+    cookie.split(';').next().unwrap().to_string()
+}
+
+/// Uploads `data` to the lobby through the HTTP API.
+pub async fn upload(client: &reqwest::Client, server: &Server, cookie: &str, filename: &str, data: &[u8]) -> reqwest::Response {
+    use base64::Engine;
+
+    client
+        .post(format!("{}/api/v1/rooms/lobby/files", server.url))
+        .header("cookie", cookie)
+        .json(&json!({
+            "filename": filename,
+            "content": base64::engine::general_purpose::STANDARD.encode(data),
+        }))
+        .send()
+        .await
+        .unwrap()
+}
+
+/// The files shared in the lobby, newest first.
+pub async fn list_files(client: &reqwest::Client, server: &Server, cookie: &str) -> Vec<Value> {
+    client
+        .get(format!("{}/api/v1/rooms/lobby/files", server.url))
+        .header("cookie", cookie)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
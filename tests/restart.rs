@@ -0,0 +1,51 @@
+//! Restarts the server on the same directory, to check what it makes of the
+//! files it finds at startup.
+
+mod common;
+
+use std::io::Cursor;
+
+use common::Server;
+use serde_json::Value;
+
+async fn thumbnail_ids(client: &reqwest::Client, server: &Server, cookie: &str) -> Vec<Value> {
+    let files = common::list_files(client, server, cookie).await;
+    files.iter().map(|file| file["thumbnail_id"].clone()).collect()
+}
+
+async fn fetch(client: &reqwest::Client, url: String, cookie: &str) -> (u16, Vec<u8>) {
+    let response = client.get(url).header("cookie", cookie).send().await.unwrap();
+    (response.status().as_u16(), response.bytes().await.unwrap().to_vec())
+}
+
+#[tokio::test]
+async fn thumbnails_survive_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let client = reqwest::Client::new();
+
+    let mut png = Vec::new();
+    image::RgbImage::from_fn(640, 480, |x, y| image::Rgb([x as u8, y as u8, 128]))
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+
+    let server = Server::start(dir.path(), &[]);
+    common::register(&client, &server, "alice").await;
+    let cookie = common::login(&client, &server, "alice").await;
+    let response = common::upload(&client, &server, &cookie, "picture.png", &png).await;
+    assert!(response.status().is_success(), "upload failed: {}", response.text().await.unwrap());
+
+    let thumbnails = thumbnail_ids(&client, &server, &cookie).await;
+    let thumbnail_id = thumbnails[0].as_str().expect("the image has a thumbnail").to_string();
+    let before = fetch(&client, format!("{}/thumbnails/{}", server.url, thumbnail_id), &cookie).await;
+    assert_eq!(before.0, 200);
+    server.stop();
+
+    // The thumbnail is a bare UUID in the uploads directory, just like an
+    // upload from before files were indexed, but must not be taken for one.
+    let server = Server::start(dir.path(), &[]);
+    let cookie = common::login(&client, &server, "alice").await;
+    assert_eq!(thumbnail_ids(&client, &server, &cookie).await, thumbnails);
+    let after = fetch(&client, format!("{}/thumbnails/{}", server.url, thumbnail_id), &cookie).await;
+    assert_eq!(after, before);
+    server.stop();
+}
@@ -0,0 +1,128 @@
+//! Uploads to a server that scans files with a stand-in for clamd.
+
+mod common;
+
+use std::time::Duration;
+
+use common::Server;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const EICAR: &[u8] = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+/// Answers `INSTREAM` requests like clamd, flagging files that contain the
+/// EICAR test string. Returns its address.
+async fn fake_clamd() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut command = [0; 10];
+                socket.read_exact(&mut command).await?;
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = Vec::new();
+                loop {
+                    let len = socket.read_u32().await? as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let start = data.len();
+                    data.resize(start + len, 0);
+                    socket.read_exact(&mut data[start..]).await?;
+                }
+                let infected = data.windows(EICAR.len()).any(|window| window == EICAR);
+                let reply: &[u8] = if infected { b"stream: Eicar-Signature FOUND\0" } else { b"stream: OK\0" };
+                socket.write_all(reply).await
+            });
+        }
+    });
+    address
+}
+
+/// Opens a long polling session, the simplest way to receive frames.
+async fn connect(client: &reqwest::Client, server: &Server, cookie: &str) -> String {
+    let session: Value = client
+        .post(format!("{}/poll", server.url))
+        .header("cookie", cookie)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    session["client_id"].as_str().unwrap().to_string()
+}
+
+/// Frames received until one of `until`'s type arrives.
+async fn poll_until(client: &reqwest::Client, server: &Server, cookie: &str, client_id: &str, until: &str) -> Vec<Value> {
+    let mut frames = Vec::new();
+    let found = tokio::time::timeout(Duration::from_secs(60), async {
+        while !frames.iter().any(|frame: &Value| frame["type"] == until) {
+            let batch: Vec<Value> = client
+                .get(format!("{}/poll/{}", server.url, client_id))
+                .header("cookie", cookie)
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            frames.extend(batch);
+        }
+    })
+    .await;
+    assert!(found.is_ok(), "no {} frame in {:?}", until, frames);
+    frames
+}
+
+fn is_notice(frame: &Value) -> bool {
+    frame["type"] == "system" && frame["content"].as_str().is_some_and(|content| content.contains("malware scanner"))
+}
+
+#[tokio::test]
+async fn infected_uploads_are_quarantined() {
+    let dir = tempfile::tempdir().unwrap();
+    let client = reqwest::Client::new();
+    let clamd = fake_clamd().await;
+    let server = Server::start(dir.path(), &[("CHAT_SCANNER", "clamd"), ("CHAT_CLAMD_ADDRESS", &clamd)]);
+
+    common::register(&client, &server, "alice").await;
+    common::register(&client, &server, "bob").await;
+    let alice = common::login(&client, &server, "alice").await;
+    let bob = common::login(&client, &server, "bob").await;
+    let alice_id = connect(&client, &server, &alice).await;
+    let bob_id = connect(&client, &server, &bob).await;
+
+    let response = common::upload(&client, &server, &alice, "totally-safe.com", EICAR).await;
+    assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Only the uploader hears about it.
+    let frames = poll_until(&client, &server, &alice, &alice_id, "system").await;
+    let notice = frames.iter().find(|frame| is_notice(frame)).expect("a notice about the file");
+    let content = notice["content"].as_str().unwrap();
+    assert!(content.contains("totally-safe.com") && content.contains("Eicar-Signature"), "{}", content);
+    let response = common::upload(&client, &server, &alice, "fine.txt", b"nothing to see here").await;
+    assert!(response.status().is_success());
+    let frames = poll_until(&client, &server, &bob, &bob_id, "file").await;
+    assert!(!frames.iter().any(is_notice), "{:?}", frames);
+
+    // Only the clean file was shared.
+    let files = common::list_files(&client, &server, &alice).await;
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0]["filename"], "fine.txt");
+
+    let quarantined: Vec<_> = std::fs::read_dir(dir.path().join("quarantine")).unwrap().map(|entry| entry.unwrap()).collect();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(std::fs::read(quarantined[0].path()).unwrap(), EICAR);
+    let db = rusqlite::Connection::open(dir.path().join("users.db")).unwrap();
+    let (id, uploader, name, signature): (String, String, String, String) = db
+        .query_row("SELECT id, uploader, original_name, signature FROM quarantine", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .unwrap();
+    assert_eq!(quarantined[0].file_name().to_str(), Some(id.as_str()));
+    assert_eq!((uploader.as_str(), name.as_str(), signature.as_str()), ("alice", "totally-safe.com", "Eicar-Signature"));
+    server.stop();
+}